@@ -0,0 +1,64 @@
+extern crate criterion;
+extern crate rqueue;
+
+use std::collections::HashMap;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rqueue::slice_map::{SliceMap, Hashing};
+
+/// topic counts from a fresh server up to a busy production one
+const SIZES: &[usize] = &[1_000, 20_000, 200_000];
+
+fn topics(n: usize) -> Vec<Vec<u8>> {
+    (0..n).map(|i| format!("orders.region-{}.account-{}", i % 97, i).into_bytes()).collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for &n in SIZES {
+        let keys = topics(n);
+        group.bench_with_input(BenchmarkId::new("SliceMap", n), &keys, |b, keys| b.iter(|| {
+            let mut map = SliceMap::new();
+            for (i, key) in keys.iter().enumerate() {
+                map.insert(key, i);
+            }
+            map
+        }));
+        group.bench_with_input(BenchmarkId::new("HashMap", n), &keys, |b, keys| b.iter(|| {
+            let mut map = HashMap::new();
+            for (i, key) in keys.iter().enumerate() {
+                map.insert(key.clone(), i);
+            }
+            map
+        }));
+    }
+    group.finish();
+}
+
+/// what fan-out does for every notification
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for &n in SIZES {
+        let keys = topics(n);
+        let mut map = SliceMap::new();
+        let mut keyed = SliceMap::with_hasher(Hashing::keyed());
+        let mut std_map = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            map.insert(key, i);
+            keyed.insert(key, i);
+            std_map.insert(key.clone(), i);
+        }
+        group.bench_function(BenchmarkId::new("SliceMap", n), |b| b.iter(|| {
+            keys.iter().step_by(n / 1000).filter_map(|key| map.get(black_box(key))).sum::<usize>()
+        }));
+        group.bench_function(BenchmarkId::new("SliceMap/keyed", n), |b| b.iter(|| {
+            keys.iter().step_by(n / 1000).filter_map(|key| keyed.get(black_box(key))).sum::<usize>()
+        }));
+        group.bench_function(BenchmarkId::new("HashMap", n), |b| b.iter(|| {
+            keys.iter().step_by(n / 1000).filter_map(|key| std_map.get(&black_box(key)[..])).sum::<usize>()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, lookup);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+extern crate criterion;
+extern crate rqueue;
+
+use std::sync::Barrier;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rqueue::slice_map::Hashing;
+use rqueue::topics::{TopicIndex, DEFAULT_SHARDS};
+
+const TOPICS: usize = 10_000;
+const SUBSCRIBERS: usize = 8;
+
+fn topic(i: usize) -> Vec<u8> {
+    format!("orders.region-{}.account-{}", i % 97, i).into_bytes()
+}
+
+fn index(shards: usize) -> TopicIndex<usize> {
+    let index = TopicIndex::new(shards, Hashing::Fast);
+    for i in 0..TOPICS {
+        for s in 0..SUBSCRIBERS {
+            index.subscribe(&topic(i), s);
+        }
+    }
+    index
+}
+
+/// {workers} threads each looking up {iters} topics, as they would to fan out notifications,
+/// while another thread keeps subscribing and unsubscribing the way the event loop does. returns
+/// how long the slowest worker took
+fn fan_out(index: &TopicIndex<usize>, workers: usize, iters: u64) -> Duration {
+    let topics = (0..TOPICS).map(topic).collect::<Vec<_>>();
+    let start = Barrier::new(workers + 1);
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut i = 0;
+            while !done.load(Ordering::Relaxed) {
+                let topic = &topics[i % TOPICS];
+                index.subscribe(topic, SUBSCRIBERS);
+                index.unsubscribe(topic, SUBSCRIBERS);
+                i += 7;
+            }
+        });
+        let handles = (0..workers).map(|w| {
+            let (topics, start) = (&topics, &start);
+            scope.spawn(move || {
+                start.wait();
+                let began = Instant::now();
+                for i in 0..iters as usize {
+                    let subscribers = index.subscribers(&topics[(i * 31 + w * 1009) % TOPICS]);
+                    black_box(subscribers.map(|s| s.len()));
+                }
+                began.elapsed()
+            })
+        }).collect::<Vec<_>>();
+        start.wait();
+        let slowest = handles.into_iter().map(|h| h.join().unwrap()).max().unwrap();
+        done.store(true, Ordering::Relaxed);
+        slowest
+    })
+}
+
+/// one shard is a single lock for every topic, the way a global lock around the index would be
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out_lookups");
+    for &shards in &[1, DEFAULT_SHARDS] {
+        let index = index(shards);
+        for &workers in &[8, 16, 32] {
+            group.throughput(Throughput::Elements(workers as u64));
+            group.bench_with_input(BenchmarkId::new(format!("{}_shards", shards), workers), &workers, |b, &workers| {
+                b.iter_custom(|iters| fan_out(&index, workers, iters))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
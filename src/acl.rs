@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use serde::Deserialize;
+use toml;
+use config::ConfigError;
+
+/// a topic pattern. topics are split into tokens on '.', "*" matches exactly one token and ">"
+/// matches one or more trailing tokens, so "payments.>" matches "payments.eu" and
+/// "payments.eu.refunds" but not "payments"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<String>
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern, String> {
+        let tokens = pattern.split('.').map(|t| t.to_owned()).collect::<Vec<_>>();
+        if tokens.iter().any(|t| t.is_empty()) {
+            return Err(format!("empty token in {:?}", pattern));
+        }
+        if let Some(i) = tokens.iter().position(|t| t == ">") {
+            if i != tokens.len() - 1 {
+                return Err(format!("\">\" has to be the last token in {:?}", pattern));
+            }
+        }
+        Ok(Pattern { tokens })
+    }
+
+    pub fn matches(&self, topic: &[u8]) -> bool {
+        let mut topic_tokens = topic.split(|b| *b == b'.');
+        for token in self.tokens.iter() {
+            match (token.as_str(), topic_tokens.next()) {
+                (">", Some(_)) => return true,
+                ("*", Some(_)) => (),
+                (_, Some(t)) if t == token.as_bytes() => (),
+                _ => return false
+            }
+        }
+        topic_tokens.next().is_none()
+    }
+}
+
+/// what one user may do
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    pub publish: Vec<Pattern>,
+    pub subscribe: Vec<Pattern>,
+
+    /// whether ADMIN requests are allowed
+    pub admin: bool
+}
+
+impl Permissions {
+    /// allows every topic. admin has to be granted explicitly
+    pub fn all() -> Permissions {
+        let everything = vec![Pattern::parse(">").unwrap()];
+        Permissions {
+            publish: everything.clone(),
+            subscribe: everything,
+            admin: false
+        }
+    }
+
+    pub fn may_publish(&self, topic: &[u8]) -> bool {
+        self.publish.iter().any(|p| p.matches(topic))
+    }
+
+    pub fn may_subscribe(&self, topic: &[u8]) -> bool {
+        self.subscribe.iter().any(|p| p.matches(topic))
+    }
+}
+
+/// per user topic permissions, read from a TOML file. users that are not listed, and clients
+/// that did not authenticate, get the default permissions, which allow nothing unless given
+///
+/// ```toml
+/// [user.billing]
+/// publish = ["invoices.*"]
+/// subscribe = ["payments.>"]
+///
+/// [user.ops]
+/// admin = true             # may send ADMIN requests
+///
+/// [default]
+/// subscribe = ["public.>"]
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    users: HashMap<String, Arc<Permissions>>,
+    default: Arc<Permissions>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AclFile {
+    user: HashMap<String, PermissionsSection>,
+    default: PermissionsSection
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PermissionsSection {
+    publish: Vec<String>,
+    subscribe: Vec<String>,
+    admin: bool
+}
+
+impl PermissionsSection {
+    fn parse(&self, name: &str) -> Result<Permissions, ConfigError> {
+        let parse = |patterns: &[String]| {
+            patterns.iter()
+                .map(|p| Pattern::parse(p).map_err(|e| ConfigError::Invalid(format!("{}: {}", name, e))))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Permissions {
+            publish: parse(&self.publish)?,
+            subscribe: parse(&self.subscribe)?,
+            admin: self.admin
+        })
+    }
+}
+
+impl Acl {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Acl, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.display().to_string(), e))?;
+        Acl::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Acl, ConfigError> {
+        let file: AclFile = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        let mut users = HashMap::new();
+        for (name, section) in file.user.iter() {
+            users.insert(name.clone(), Arc::new(section.parse(&format!("user.{}", name))?));
+        }
+        Ok(Acl {
+            users,
+            default: Arc::new(file.default.parse("default")?)
+        })
+    }
+
+    /// allows everyone everything, what the server uses without an ACL
+    pub fn allow_all() -> Acl {
+        Acl {
+            users: HashMap::new(),
+            default: Arc::new(Permissions::all())
+        }
+    }
+
+    /// the permissions of {user}, None meaning a client that did not authenticate
+    pub fn permissions(&self, user: Option<&str>) -> Arc<Permissions> {
+        user.and_then(|u| self.users.get(u)).unwrap_or(&self.default).clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Acl, Pattern};
+
+    fn matches(pattern: &str, topic: &str) -> bool {
+        Pattern::parse(pattern).unwrap().matches(topic.as_bytes())
+    }
+
+    #[test]
+    fn patterns_match_tokens() {
+        assert!(matches("invoices", "invoices"));
+        assert!(!matches("invoices", "invoices.eu"));
+        assert!(matches("invoices.*", "invoices.eu"));
+        assert!(!matches("invoices.*", "invoices"));
+        assert!(!matches("invoices.*", "invoices.eu.paid"));
+        assert!(matches("*.eu", "invoices.eu"));
+        assert!(matches("payments.>", "payments.eu"));
+        assert!(matches("payments.>", "payments.eu.refunds"));
+        assert!(!matches("payments.>", "payments"));
+        assert!(!matches("payments.>", "paymentsx.eu"));
+        assert!(matches(">", "anything.at.all"));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        for pattern in &["", "a..b", "a.>.b", "a."] {
+            assert!(Pattern::parse(pattern).is_err(), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn unlisted_users_get_the_default() {
+        let acl = Acl::parse("[user.billing]\npublish = [\"invoices.*\"]\nsubscribe = [\"payments.>\"]\n\
+                              [default]\nsubscribe = [\"public.>\"]\n").unwrap();
+        let billing = acl.permissions(Some("billing"));
+        assert!(billing.may_publish(b"invoices.eu"));
+        assert!(!billing.may_subscribe(b"invoices.eu"));
+        assert!(billing.may_subscribe(b"payments.eu.refunds"));
+
+        for user in &[Some("someone"), None] {
+            let permissions = acl.permissions(*user);
+            assert!(!permissions.may_publish(b"invoices.eu"));
+            assert!(permissions.may_subscribe(b"public.news"));
+        }
+    }
+
+    #[test]
+    fn nothing_is_allowed_by_default() {
+        let acl = Acl::parse("").unwrap();
+        assert!(!acl.permissions(None).may_subscribe(b"t"));
+        assert!(!Acl::allow_all().permissions(None).admin);
+
+        let acl = Acl::parse("[user.ops]\nadmin = true\n").unwrap();
+        assert!(acl.permissions(Some("ops")).admin);
+        assert!(!acl.permissions(Some("ops")).may_publish(b"t"));
+    }
+}
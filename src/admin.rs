@@ -0,0 +1,261 @@
+use std::convert::TryInto;
+use protocol::{PREAMBLE_LEN_SZ, MAX_PAYLOAD_SZ, ADMIN, ADMIN_REPLY};
+
+// admin commands, the first byte of an ADMIN payload and of every ADMIN_REPLY it gets
+pub const LIST_CLIENTS  : u8 = 1; // every connected client
+pub const LIST_TOPICS   : u8 = 2; // every topic with its subscriber count
+pub const SUBSCRIPTIONS : u8 = 3; // the topics one client is subscribed to
+pub const DISCONNECT    : u8 = 4; // closes one client's connection
+
+/// bytes of an ADMIN_REPLY payload taken up by the command and the last flag
+const REPLY_HEADER_SZ: usize = 2;
+
+/// an admin request, sent in an ADMIN frame. only users the ACL marks as admins may send them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    ListClients,
+    ListTopics,
+
+    /// by client id, as listed by ListClients
+    Subscriptions(usize),
+    Disconnect(usize)
+}
+
+impl Request {
+    pub fn command(&self) -> u8 {
+        match *self {
+            Request::ListClients => LIST_CLIENTS,
+            Request::ListTopics => LIST_TOPICS,
+            Request::Subscriptions(_) => SUBSCRIPTIONS,
+            Request::Disconnect(_) => DISCONNECT
+        }
+    }
+
+    /// reads a request from an ADMIN payload
+    pub fn parse(payload: &[u8]) -> Option<Request> {
+        let client = || payload.get(1..9).map(|id| u64::from_be_bytes(id.try_into().unwrap()) as usize);
+        match (*payload.first()?, payload.len()) {
+            (LIST_CLIENTS, 1) => Some(Request::ListClients),
+            (LIST_TOPICS, 1) => Some(Request::ListTopics),
+            (SUBSCRIPTIONS, 9) => client().map(Request::Subscriptions),
+            (DISCONNECT, 9) => client().map(Request::Disconnect),
+            _ => None
+        }
+    }
+
+    /// the ADMIN frame for this request
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut payload = vec![self.command()];
+        match *self {
+            Request::Subscriptions(id) | Request::Disconnect(id) => payload.extend_from_slice(&(id as u64).to_be_bytes()),
+            _ => ()
+        }
+        let mut frame = (payload.len() as u16).to_be_bytes().to_vec();
+        frame.push(ADMIN);
+        frame.extend(payload);
+        frame
+    }
+}
+
+/// one item of an ADMIN_REPLY
+pub trait Entry: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// reads one entry off the front of {bytes}
+    fn decode(bytes: &mut &[u8]) -> Option<Self>;
+}
+
+/// a connected client, as listed by LIST_CLIENTS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub id: usize,
+    pub addr: String,
+
+    /// who the client authenticated as
+    pub user: Option<String>,
+    pub subscriptions: usize,
+
+    /// notifications the workers have yet to fan out
+    pub in_flight: usize,
+
+    /// writes of the client's notifications to subscribers
+    pub delivered: u64
+}
+
+/// a topic, as listed by LIST_TOPICS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicInfo {
+    pub topic: Vec<u8>,
+    pub subscribers: usize
+}
+
+impl Entry for ClientInfo {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.id as u64).to_be_bytes());
+        put_bytes(out, self.addr.as_bytes());
+        put_bytes(out, self.user.as_ref().map_or(&b""[..], |u| u.as_bytes()));
+        out.extend_from_slice(&(self.subscriptions as u32).to_be_bytes());
+        out.extend_from_slice(&(self.in_flight as u32).to_be_bytes());
+        out.extend_from_slice(&self.delivered.to_be_bytes());
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Option<ClientInfo> {
+        let id = u64::from_be_bytes(take(bytes, 8)?.try_into().unwrap()) as usize;
+        let addr = String::from_utf8_lossy(take_bytes(bytes)?).into_owned();
+        let user = String::from_utf8_lossy(take_bytes(bytes)?).into_owned();
+        let subscriptions = u32::from_be_bytes(take(bytes, 4)?.try_into().unwrap()) as usize;
+        let in_flight = u32::from_be_bytes(take(bytes, 4)?.try_into().unwrap()) as usize;
+        let delivered = u64::from_be_bytes(take(bytes, 8)?.try_into().unwrap());
+        Some(ClientInfo {
+            id,
+            addr,
+            user: if user.is_empty() { None } else { Some(user) },
+            subscriptions,
+            in_flight,
+            delivered
+        })
+    }
+}
+
+impl Entry for TopicInfo {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_bytes(out, &self.topic);
+        out.extend_from_slice(&(self.subscribers as u32).to_be_bytes());
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Option<TopicInfo> {
+        let topic = take_bytes(bytes)?.to_vec();
+        let subscribers = u32::from_be_bytes(take(bytes, 4)?.try_into().unwrap()) as usize;
+        Some(TopicInfo { topic, subscribers })
+    }
+}
+
+/// a topic, as listed by SUBSCRIPTIONS
+impl Entry for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_bytes(out, self);
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Option<Vec<u8>> {
+        take_bytes(bytes).map(|b| b.to_vec())
+    }
+}
+
+/// a string prefixed with its length in two bytes, which holds any payload whole
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if bytes.len() < n {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Some(head)
+}
+
+fn take_bytes<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes(take(bytes, 2)?.try_into().unwrap()) as usize;
+    take(bytes, len)
+}
+
+/// the ADMIN_REPLY frames answering {command}. entries are packed into as few frames as fit, the
+/// last one is flagged so the client knows when the reply is complete. there is always at least
+/// one frame. every entry fits in a frame of its own, topics and user names are at most 255 bytes
+pub fn reply_frames<E: Entry>(command: u8, entries: &[E]) -> Vec<Vec<u8>> {
+    let mut payloads = vec![vec![command, 0]];
+    let mut entry = Vec::new();
+    for e in entries {
+        entry.clear();
+        e.encode(&mut entry);
+        let len = payloads.last().unwrap().len();
+        if len > REPLY_HEADER_SZ && len + entry.len() > MAX_PAYLOAD_SZ {
+            payloads.push(vec![command, 0]);
+        }
+        payloads.last_mut().unwrap().extend_from_slice(&entry);
+    }
+    payloads.last_mut().unwrap()[1] = 1;
+
+    payloads.into_iter().map(|payload| {
+        assert!(payload.len() <= MAX_PAYLOAD_SZ, "admin reply payload of {} bytes", payload.len());
+        let mut frame = Vec::with_capacity(PREAMBLE_LEN_SZ + 1 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.push(ADMIN_REPLY);
+        frame.extend(payload);
+        frame
+    }).collect()
+}
+
+/// reads an ADMIN_REPLY payload: the command it answers, whether it is the last frame of the
+/// reply, and its entries
+pub fn parse_reply<E: Entry>(payload: &[u8]) -> Option<(u8, bool, Vec<E>)> {
+    if payload.len() < REPLY_HEADER_SZ {
+        return None;
+    }
+    let mut bytes = &payload[REPLY_HEADER_SZ..];
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        entries.push(E::decode(&mut bytes)?);
+    }
+    Some((payload[0], payload[1] == 1, entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Request, ClientInfo, TopicInfo, reply_frames, parse_reply, LIST_CLIENTS, LIST_TOPICS, SUBSCRIPTIONS};
+    use protocol::{PREAMBLE_SZ, MAX_PAYLOAD_SZ};
+
+    #[test]
+    fn requests_survive_a_round_trip() {
+        for request in &[Request::ListClients, Request::ListTopics, Request::Subscriptions(7), Request::Disconnect(1 << 40)] {
+            let frame = request.to_frame();
+            assert_eq!(Request::parse(&frame[PREAMBLE_SZ..]), Some(*request));
+        }
+        assert_eq!(Request::parse(&[LIST_CLIENTS, 0]), None);
+        assert_eq!(Request::parse(&[99]), None);
+    }
+
+    #[test]
+    fn long_replies_are_split_over_frames() {
+        let topics = (0..200).map(|i| TopicInfo { topic: vec![b'a' + (i % 26) as u8; 20], subscribers: i })
+                             .collect::<Vec<_>>();
+        let frames = reply_frames(LIST_TOPICS, &topics);
+        assert!(frames.len() > 1);
+
+        let mut decoded = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let (command, last, entries) = parse_reply::<TopicInfo>(&frame[PREAMBLE_SZ..]).unwrap();
+            assert_eq!(command, LIST_TOPICS);
+            assert_eq!(last, i == frames.len() - 1);
+            decoded.extend(entries);
+        }
+        assert_eq!(decoded, topics);
+    }
+
+    #[test]
+    fn the_largest_entries_fit_in_a_frame() {
+        let topics = vec![vec![b'a'; 255]; 20];
+        for frame in reply_frames(SUBSCRIPTIONS, &topics) {
+            assert!(frame.len() - PREAMBLE_SZ <= MAX_PAYLOAD_SZ);
+        }
+        let client = ClientInfo {
+            id: usize::MAX,
+            addr: format!("unix:/{}", "a".repeat(107)),
+            user: Some("u".repeat(255)),
+            subscriptions: 0,
+            in_flight: 0,
+            delivered: 0
+        };
+        assert!(reply_frames(LIST_CLIENTS, &vec![client; 10]).len() > 1);
+    }
+
+    #[test]
+    fn empty_replies_are_one_frame() {
+        let frames = reply_frames::<ClientInfo>(LIST_CLIENTS, &[]);
+        assert_eq!(frames.len(), 1);
+        let (_, last, entries) = parse_reply::<ClientInfo>(&frames[0][PREAMBLE_SZ..]).unwrap();
+        assert!(last && entries.is_empty());
+    }
+}
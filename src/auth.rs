@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+use std::{fs, io, thread};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, channel};
+use bcrypt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use toml;
+use config::ConfigError;
+
+/// bcrypt cost used by hash_password. password checks run one at a time on the Authenticator's
+/// thread, so every one of them holds up the AUTHs behind it and it is kept below bcrypt's default
+pub const HASH_COST: u32 = 10;
+
+const TOKEN_PREFIX: &str = "sha256:";
+
+/// a client identity, as listed in the credentials file
+#[derive(Debug, PartialEq, Eq)]
+pub struct User {
+    pub name: String
+}
+
+/// users that may connect, read from a TOML file. passwords are stored as bcrypt hashes, tokens
+/// (long random secrets) as sha256 digests
+///
+/// ```toml
+/// [[user]]
+/// name = "alice"
+/// password = "$2b$10$..."   # server --hash-password
+///
+/// [[user]]
+/// name = "ingest"
+/// token = "sha256:..."      # server --hash-token
+/// ```
+pub struct Credentials {
+    /// by name, with the password hash
+    passwords: HashMap<String, (Arc<User>, String)>,
+
+    /// by token digest
+    tokens: HashMap<Vec<u8>, Arc<User>>,
+
+    /// one of the password hashes, checked against for unknown users so that they take as long
+    /// to turn away as known ones and names can't be guessed by timing
+    decoy: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    #[serde(default)]
+    user: Vec<UserSection>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserSection {
+    name: String,
+    password: Option<String>,
+    token: Option<String>
+}
+
+impl Credentials {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Credentials, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.display().to_string(), e))?;
+        Credentials::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Credentials, ConfigError> {
+        let file: CredentialsFile = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        let mut credentials = Credentials {
+            passwords: HashMap::new(),
+            tokens: HashMap::new(),
+            decoy: None
+        };
+        let mut names = HashSet::new();
+        for section in file.user {
+            if !names.insert(section.name.clone()) {
+                return Err(ConfigError::Invalid(format!("user {} is listed more than once", section.name)));
+            }
+            if section.password.is_none() && section.token.is_none() {
+                return Err(ConfigError::Invalid(format!("user {} needs a password or a token", section.name)));
+            }
+            let user = Arc::new(User { name: section.name });
+            if let Some(password) = section.password {
+                if password.parse::<bcrypt::HashParts>().is_err() {
+                    return Err(ConfigError::Invalid(format!("the password of user {} is not a bcrypt hash", user.name)));
+                }
+                credentials.decoy.get_or_insert_with(|| password.clone());
+                credentials.passwords.insert(user.name.clone(), (user.clone(), password));
+            }
+            if let Some(token) = section.token {
+                let digest = token.strip_prefix(TOKEN_PREFIX).and_then(from_hex).filter(|d| d.len() == 32);
+                match digest {
+                    Some(digest) => credentials.tokens.insert(digest, user.clone()),
+                    None => return Err(ConfigError::Invalid(format!("the token of user {} is not a {} digest",
+                                                                    user.name, TOKEN_PREFIX)))
+                };
+            }
+        }
+        Ok(credentials)
+    }
+
+    /// checks a name and password, or a token when {name} is empty
+    pub fn authenticate(&self, name: &[u8], secret: &[u8]) -> Option<Arc<User>> {
+        if name.is_empty() {
+            return self.tokens.get(&Sha256::digest(secret)[..]).cloned();
+        }
+        let (user, hash) = match self.passwords.get(String::from_utf8_lossy(name).as_ref()) {
+            Some((user, hash)) => (Some(user), hash),
+            None => (None, self.decoy.as_ref()?)
+        };
+        match bcrypt::verify(secret, hash) {
+            Ok(true) => user.cloned(),
+            _ => None
+        }
+    }
+}
+
+/// checks credentials on a thread of its own, so that bcrypt never holds up the event loop. the
+/// thread stops once the Authenticator is dropped
+pub struct Authenticator {
+    requests: Sender<(usize, Vec<u8>, Vec<u8>)>
+}
+
+impl Authenticator {
+    /// checks against {credentials}, handing {done} the id each check was asked for with and the
+    /// user, if the credentials were good
+    pub fn start<F>(credentials: Arc<Credentials>, done: F) -> io::Result<Authenticator>
+    where F: Fn(usize, Option<Arc<User>>) + Send + 'static {
+        let (requests, pending) = channel::<(usize, Vec<u8>, Vec<u8>)>();
+        thread::Builder::new().name("rqueue-auth".to_owned()).spawn(move || {
+            for (id, name, secret) in pending {
+                done(id, credentials.authenticate(&name, &secret));
+            }
+        })?;
+        Ok(Authenticator { requests })
+    }
+
+    /// checks {name} and {secret} as Credentials::authenticate does, for {id}
+    pub fn check(&self, id: usize, name: &[u8], secret: &[u8]) {
+        let _ = self.requests.send((id, name.to_vec(), secret.to_vec()));
+    }
+}
+
+/// a bcrypt hash of {password} for the credentials file
+pub fn hash_password(password: &str) -> String {
+    bcrypt::hash(password, HASH_COST).expect("HASH_COST is a valid cost")
+}
+
+/// a digest of {token} for the credentials file
+pub fn hash_token(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    let hex = digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}{}", TOKEN_PREFIX, hex)
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Credentials, Authenticator, hash_token};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use config::ConfigError;
+    use bcrypt;
+
+    fn credentials() -> Credentials {
+        // the minimum cost keeps the tests fast
+        let password = bcrypt::hash("hunter2", 4).unwrap();
+        Credentials::parse(&format!("[[user]]\nname = \"alice\"\npassword = \"{}\"\n\
+                                     [[user]]\nname = \"ingest\"\ntoken = \"{}\"\n",
+                                    password, hash_token("s3cr3t-t0k3n"))).unwrap()
+    }
+
+    #[test]
+    fn accepts_passwords_and_tokens() {
+        let credentials = credentials();
+        assert_eq!(credentials.authenticate(b"alice", b"hunter2").unwrap().name, "alice");
+        assert_eq!(credentials.authenticate(b"", b"s3cr3t-t0k3n").unwrap().name, "ingest");
+    }
+
+    #[test]
+    fn rejects_wrong_secrets() {
+        let credentials = credentials();
+        assert!(credentials.authenticate(b"alice", b"hunter3").is_none());
+        assert!(credentials.authenticate(b"bob", b"hunter2").is_none());
+        assert!(credentials.authenticate(b"ingest", b"s3cr3t-t0k3n").is_none());
+        assert!(credentials.authenticate(b"", b"hunter2").is_none());
+    }
+
+    #[test]
+    fn checks_in_the_background() {
+        let (done, checked) = channel();
+        let authenticator = Authenticator::start(Arc::new(credentials()), move |id, user| {
+            done.send((id, user.map(|u| u.name.clone()))).unwrap();
+        }).unwrap();
+        authenticator.check(1, b"alice", b"hunter2");
+        authenticator.check(2, b"bob", b"hunter2");
+        assert_eq!(checked.recv_timeout(Duration::from_secs(5)), Ok((1, Some("alice".to_owned()))));
+        assert_eq!(checked.recv_timeout(Duration::from_secs(5)), Ok((2, None)));
+    }
+
+    #[test]
+    fn rejects_plaintext_secrets() {
+        for contents in &["[[user]]\nname = \"alice\"\npassword = \"hunter2\"\n",
+                          "[[user]]\nname = \"alice\"\ntoken = \"s3cr3t\"\n",
+                          "[[user]]\nname = \"alice\"\n"] {
+            match Credentials::parse(contents) {
+                Err(ConfigError::Invalid(_)) => (),
+                Err(e) => panic!("expected {:?} to be invalid, got {}", contents, e),
+                Ok(_) => panic!("expected {:?} to be invalid", contents)
+            }
+        }
+    }
+}
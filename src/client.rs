@@ -0,0 +1,397 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{self, ClientConnection, ServerName, StreamOwned};
+use protocol::{PREAMBLE_SZ, MAX_PAYLOAD_SZ, MAX_TOPIC_SZ, NOTIFICATION, ERROR, AUTH_OK, ADMIN_REPLY, PUBACK, PUBNACK, u8_2_to_usize};
+use protocol::{HEADERS, FEATURE_HEADERS, ERR_PERMISSION_DENIED, ERR_NOT_FOUND, ERR_RATE_LIMITED, parse_publish};
+use protocol::{headers_message, features_message, split_headers, parse_headers};
+use admin::{self, Request, Entry, ClientInfo, TopicInfo};
+use protocol::{subscribe_message, remove_message, notify_message, deregister_message, auth_message, publish_message};
+use protocol::publish_headers_message;
+use transport::ListenAddr;
+
+/// a message received from the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub m_type: u8,
+
+    /// everything after the preamble
+    pub payload: Vec<u8>
+}
+
+impl Message {
+    /// the topic of a NOTIFICATION or HEADERS
+    pub fn topic(&self) -> Option<&[u8]> {
+        self.split().map(|(topic, _)| topic)
+    }
+
+    /// the content of a NOTIFICATION or HEADERS
+    pub fn content(&self) -> Option<&[u8]> {
+        self.split().map(|(_, content)| content)
+    }
+
+    /// the headers of a HEADERS, in the order they were sent
+    pub fn headers(&self) -> Option<Vec<(&[u8], &[u8])>> {
+        if self.m_type != HEADERS {
+            return None;
+        }
+        split_headers(&self.payload).and_then(|(_, block, _)| parse_headers(block))
+    }
+
+    /// the first value of the header {key}
+    pub fn header(&self, key: &[u8]) -> Option<&[u8]> {
+        self.headers()?.into_iter().find(|&(k, _)| k == key).map(|(_, value)| value)
+    }
+
+    /// the code and reason of an ERROR
+    pub fn error(&self) -> Option<(u8, String)> {
+        if self.m_type != ERROR || self.payload.is_empty() {
+            return None;
+        }
+        Some((self.payload[0], String::from_utf8_lossy(&self.payload[1..]).into_owned()))
+    }
+
+    /// the sequence number a PUBACK confirms
+    pub fn ack(&self) -> Option<u32> {
+        if self.m_type != PUBACK {
+            return None;
+        }
+        parse_publish(&self.payload).map(|(seq, _)| seq)
+    }
+
+    /// the sequence number, code and reason of a PUBNACK
+    pub fn nack(&self) -> Option<(u32, u8, String)> {
+        if self.m_type != PUBNACK {
+            return None;
+        }
+        let (seq, rest) = parse_publish(&self.payload)?;
+        let (code, reason) = rest.split_first()?;
+        Some((seq, *code, String::from_utf8_lossy(reason).into_owned()))
+    }
+
+    fn split(&self) -> Option<(&[u8], &[u8])> {
+        if self.m_type == HEADERS {
+            return split_headers(&self.payload).map(|(topic, _, content)| (topic, content));
+        }
+        if self.m_type != NOTIFICATION || self.payload.is_empty() {
+            return None;
+        }
+        let topic_len = self.payload[0] as usize;
+        if self.payload.len() < topic_len + 1 {
+            return None;
+        }
+        Some((&self.payload[1..topic_len + 1], &self.payload[topic_len + 1..]))
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>)
+}
+
+/// a blocking connection to an rqueue server, over tcp, TLS or a unix socket. the framing is the
+/// same either way
+pub struct Client {
+    stream: Stream,
+
+    /// the sequence number of the next confirmed publish
+    next_seq: u32,
+
+    /// confirmed publishes the server has not answered yet
+    unconfirmed: HashSet<u32>,
+
+    /// messages that arrived while waiting for confirmations, next_message returns them first
+    received: VecDeque<Message>
+}
+
+impl Client {
+    fn new(stream: Stream) -> Client {
+        Client {
+            stream,
+            next_seq: 0,
+            unconfirmed: HashSet::new(),
+            received: VecDeque::new()
+        }
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Client::new(Stream::Tcp(stream)))
+    }
+
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<Client> {
+        Ok(Client::new(Stream::Unix(UnixStream::connect(path)?)))
+    }
+
+    /// connects to a TLS listener, checking its certificate against {server_name}. see
+    /// tls::client_config for building {config}
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, server_name: &str, config: Arc<rustls::ClientConfig>) -> io::Result<Client> {
+        let name = ServerName::try_from(server_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut conn = ClientConnection::new(config, name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut sock = TcpStream::connect(addr)?;
+        sock.set_nodelay(true)?;
+        // handshakes up front, so that certificate problems surface here
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        Ok(Client::new(Stream::Tls(Box::new(StreamOwned::new(conn, sock)))))
+    }
+
+    /// connects to a listener address as the server reports it
+    pub fn connect_to(addr: &ListenAddr) -> io::Result<Client> {
+        match *addr {
+            ListenAddr::Tcp(addr) => Client::connect(addr),
+            ListenAddr::Unix(ref path) => Client::connect_unix(path)
+        }
+    }
+
+    /// how long next_message waits before failing with WouldBlock or TimedOut, None to wait forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref s) => s.set_read_timeout(timeout),
+            Stream::Unix(ref s) => s.set_read_timeout(timeout),
+            Stream::Tls(ref s) => s.sock.set_read_timeout(timeout)
+        }
+    }
+
+    /// authenticates with a user name and password, waiting for the server's answer. call this
+    /// before anything else
+    pub fn authenticate(&mut self, user: &str, password: &str) -> io::Result<()> {
+        if user.is_empty() || user.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "user names are 1 to 255 bytes"));
+        }
+        self.auth(user.as_bytes(), password.as_bytes())
+    }
+
+    /// authenticates with a token, waiting for the server's answer. call this before anything else
+    pub fn authenticate_token(&mut self, token: &str) -> io::Result<()> {
+        self.auth(b"", token.as_bytes())
+    }
+
+    fn auth(&mut self, user: &[u8], secret: &[u8]) -> io::Result<()> {
+        check_len(1 + user.len() + secret.len())?;
+        self.write_all(&auth_message(user, secret))?;
+        let reply = self.next_message()?;
+        match reply.m_type {
+            AUTH_OK => Ok(()),
+            _ => {
+                let reason = reply.error().map(|(_, reason)| reason).unwrap_or_else(|| "unexpected reply".to_owned());
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+            }
+        }
+    }
+
+    pub fn subscribe(&mut self, topic: &[u8]) -> io::Result<()> {
+        if topic.len() > MAX_TOPIC_SZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "topics are at most 255 bytes"));
+        }
+        self.write_all(&subscribe_message(topic))
+    }
+
+    pub fn unsubscribe(&mut self, topic: &[u8]) -> io::Result<()> {
+        check_len(topic.len())?;
+        self.write_all(&remove_message(topic))
+    }
+
+    /// drops every subscription of this connection
+    pub fn deregister(&mut self) -> io::Result<()> {
+        self.write_all(&deregister_message())
+    }
+
+    /// sends {content} to every subscriber of {topic}
+    pub fn publish(&mut self, topic: &[u8], content: &[u8]) -> io::Result<()> {
+        if topic.len() > MAX_TOPIC_SZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "topics are at most 255 bytes"));
+        }
+        check_len(1 + topic.len() + content.len())?;
+        self.write_all(&notify_message(topic, content))
+    }
+
+    /// like publish, with {headers} for the subscribers that asked for them. the rest get the
+    /// plain notification
+    pub fn publish_with_headers(&mut self, topic: &[u8], headers: &[(&[u8], &[u8])], content: &[u8]) -> io::Result<()> {
+        if topic.len() > MAX_TOPIC_SZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "topics are at most 255 bytes"));
+        }
+        check_len(1 + topic.len() + 2 + block_len(headers)? + content.len())?;
+        self.write_all(&headers_message(topic, headers, content))
+    }
+
+    /// asks the server to deliver notifications with their headers, as HEADERS messages
+    pub fn enable_headers(&mut self) -> io::Result<()> {
+        self.write_all(&features_message(FEATURE_HEADERS))
+    }
+
+    /// like publish, but the server answers with a PUBACK once the notification has been routed,
+    /// or a PUBNACK if it was dropped. returns the sequence number the answer will carry without
+    /// waiting for it, so that any number can be in flight, see wait_confirmed
+    pub fn publish_confirmed(&mut self, topic: &[u8], content: &[u8]) -> io::Result<u32> {
+        if topic.len() > MAX_TOPIC_SZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "topics are at most 255 bytes"));
+        }
+        check_len(4 + 1 + topic.len() + content.len())?;
+        let seq = self.next_seq;
+        self.write_all(&publish_message(seq, topic, content))?;
+        self.next_seq = seq.wrapping_add(1);
+        self.unconfirmed.insert(seq);
+        Ok(seq)
+    }
+
+    /// like publish_confirmed, with {headers} for the subscribers that asked for them
+    pub fn publish_confirmed_with_headers(&mut self, topic: &[u8], headers: &[(&[u8], &[u8])], content: &[u8]) -> io::Result<u32> {
+        if topic.len() > MAX_TOPIC_SZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "topics are at most 255 bytes"));
+        }
+        check_len(4 + 1 + topic.len() + 2 + block_len(headers)? + content.len())?;
+        let seq = self.next_seq;
+        self.write_all(&publish_headers_message(seq, topic, headers, content))?;
+        self.next_seq = seq.wrapping_add(1);
+        self.unconfirmed.insert(seq);
+        Ok(seq)
+    }
+
+    /// blocks until the server has answered every publish_confirmed so far, failing on the first
+    /// PUBNACK. the rest are still waited for by the next call. other messages that arrive
+    /// meanwhile are kept for next_message
+    pub fn wait_confirmed(&mut self) -> io::Result<()> {
+        while !self.unconfirmed.is_empty() {
+            let message = self.read_message()?;
+            if let Some((_, code, reason)) = message.nack() {
+                let kind = match code {
+                    ERR_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+                    ERR_RATE_LIMITED => io::ErrorKind::WouldBlock,
+                    _ => io::ErrorKind::Other
+                };
+                return Err(io::Error::new(kind, reason));
+            }
+            if message.ack().is_none() {
+                self.received.push_back(message);
+            }
+        }
+        Ok(())
+    }
+
+    /// confirmed publishes the server has not answered yet
+    pub fn unconfirmed(&self) -> usize {
+        self.unconfirmed.len()
+    }
+
+    /// every connected client. needs a user the ACL marks as admin, as do the other admin
+    /// requests. notifications that arrive while waiting for the reply are discarded, so admin
+    /// tools are best kept on a connection of their own
+    pub fn list_clients(&mut self) -> io::Result<Vec<ClientInfo>> {
+        self.admin(Request::ListClients)
+    }
+
+    /// every topic that has subscribers
+    pub fn list_topics(&mut self) -> io::Result<Vec<TopicInfo>> {
+        self.admin(Request::ListTopics)
+    }
+
+    /// the topics client {id} is subscribed to
+    pub fn subscriptions_of(&mut self, id: usize) -> io::Result<Vec<Vec<u8>>> {
+        self.admin(Request::Subscriptions(id))
+    }
+
+    /// closes client {id}'s connection
+    pub fn disconnect(&mut self, id: usize) -> io::Result<()> {
+        self.admin::<ClientInfo>(Request::Disconnect(id)).map(|_| ())
+    }
+
+    fn admin<E: Entry>(&mut self, request: Request) -> io::Result<Vec<E>> {
+        self.write_all(&request.to_frame())?;
+        let mut entries = Vec::new();
+        loop {
+            let reply = self.next_message()?;
+            if let Some((code, reason)) = reply.error() {
+                let kind = match code {
+                    ERR_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+                    ERR_NOT_FOUND => io::ErrorKind::NotFound,
+                    _ => io::ErrorKind::InvalidInput
+                };
+                return Err(io::Error::new(kind, reason));
+            }
+            if reply.m_type != ADMIN_REPLY {
+                continue;
+            }
+            match admin::parse_reply::<E>(&reply.payload) {
+                Some((command, last, mut more)) if command == request.command() => {
+                    entries.append(&mut more);
+                    if last {
+                        return Ok(entries);
+                    }
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed ADMIN_REPLY"))
+            }
+        }
+    }
+
+    /// blocks until the next message from the server arrives
+    pub fn next_message(&mut self) -> io::Result<Message> {
+        match self.received.pop_front() {
+            Some(message) => Ok(message),
+            None => self.read_message()
+        }
+    }
+
+    /// reads a message off of the connection, settling confirmations as they go by
+    fn read_message(&mut self) -> io::Result<Message> {
+        let mut preamble = [0u8; PREAMBLE_SZ];
+        self.read_exact(&mut preamble)?;
+        let mut payload = vec![0u8; u8_2_to_usize(&preamble)];
+        self.read_exact(&mut payload)?;
+        let message = Message {
+            m_type: preamble[PREAMBLE_SZ - 1],
+            payload
+        };
+        if let Some(seq) = message.ack().or_else(|| message.nack().map(|(seq, _, _)| seq)) {
+            self.unconfirmed.remove(&seq);
+        }
+        Ok(message)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.write_all(bytes),
+            Stream::Unix(ref mut s) => s.write_all(bytes),
+            Stream::Tls(ref mut s) => s.write_all(bytes)
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.read_exact(buf),
+            Stream::Unix(ref mut s) => s.read_exact(buf),
+            Stream::Tls(ref mut s) => s.read_exact(buf)
+        }
+    }
+}
+
+fn check_len(payload: usize) -> io::Result<()> {
+    if payload > MAX_PAYLOAD_SZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("payload of {} bytes exceeds the limit of {}", payload, MAX_PAYLOAD_SZ)));
+    }
+    Ok(())
+}
+
+/// the size of the header block {headers} make
+fn block_len(headers: &[(&[u8], &[u8])]) -> io::Result<usize> {
+    let mut len = 0;
+    for &(key, value) in headers {
+        if key.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "header keys are at most 255 bytes"));
+        }
+        len += 1 + key.len() + 2 + value.len();
+    }
+    Ok(len)
+}
@@ -0,0 +1,348 @@
+extern crate time;
+extern crate getopts;
+extern crate rqueue;
+
+use std::{env, thread};
+use std::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use getopts::Options;
+use rqueue::protocol;
+use rqueue::protocol::{u8_2_to_usize, NOTIFICATION, PREAMBLE_SZ, PREAMBLE_LEN_SZ, MAX_STATIC_SZ};
+
+/// every published payload starts with the send time, used to measure end-to-end latency
+const TIMESTAMP_SZ: usize = 8;
+
+/// how long subscribers keep draining after the publishers stop
+const DRAIN_MS: u64 = 1000;
+
+/// number of linear sub-buckets per power of two. 7 bits keeps the relative error under 1%
+const SUB_BITS: u32 = 7;
+const SUB_COUNT: usize = 1 << SUB_BITS;
+
+/// a log-linear latency histogram (in the spirit of HdrHistogram), values are nanoseconds.
+/// values below 2 * SUB_COUNT are recorded exactly, larger ones lose precision in proportion to
+/// their magnitude
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum: u64,
+    min: u64,
+    max: u64
+}
+
+impl Histogram {
+    fn new () -> Histogram {
+        Histogram {
+            counts: vec![0; (64 - SUB_BITS as usize) * SUB_COUNT + SUB_COUNT],
+            total: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0
+        }
+    }
+
+    fn index (value: u64) -> usize {
+        let bits = 64 - value.leading_zeros();
+        if bits <= SUB_BITS + 1 {
+            value as usize
+        } else {
+            let shift = bits - (SUB_BITS + 1);
+            shift as usize * SUB_COUNT + (value >> shift) as usize
+        }
+    }
+
+    /// the highest value that maps to the bucket at {index}
+    fn highest_equivalent (index: usize) -> u64 {
+        if index < 2 * SUB_COUNT {
+            index as u64
+        } else {
+            let shift = index / SUB_COUNT - 1;
+            let sub = (index - shift * SUB_COUNT) as u64;
+            ((sub + 1) << shift) - 1
+        }
+    }
+
+    fn record (&mut self, value: u64) {
+        self.counts[Self::index(value)] += 1;
+        self.total += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn merge (&mut self, other: &Histogram) {
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += *o;
+        }
+        self.total += other.total;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// the value at quantile {q} (0.0 - 1.0)
+    fn percentile (&self, q: f64) -> u64 {
+        if self.total == 0 {
+            return 0
+        }
+        let rank = ((q * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            seen += *c;
+            if seen >= rank {
+                return Self::highest_equivalent(i).min(self.max)
+            }
+        }
+        self.max
+    }
+
+    fn mean (&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            t => self.sum as f64 / t as f64
+        }
+    }
+}
+
+struct Settings {
+    addr: String,
+    publishers: usize,
+    subscribers: usize,
+    topics: usize,
+    sizes: Vec<usize>,
+    rate: u64,
+    duration: u64
+}
+
+#[derive(Default)]
+struct PublisherStats {
+    sent: u64,
+    bytes: u64
+}
+
+struct SubscriberStats {
+    received: u64,
+    bytes: u64,
+    latency: Histogram
+}
+
+fn topic_name (i: usize) -> Vec<u8> {
+    format!("bench.{}", i).into_bytes()
+}
+
+/// publishes round robin over all topics until {stop} is set, optionally rate limited
+fn publish (settings: Arc<Settings>, id: usize, stop: Arc<AtomicBool>) -> io::Result<PublisherStats> {
+    let mut stream = TcpStream::connect(&settings.addr[..])?;
+    let _ = stream.set_nodelay(true);
+    let topics = (0..settings.topics).map(topic_name).collect::<Vec<_>>();
+    let interval = match settings.rate {
+        0 => 0,
+        r => 1_000_000_000 / r
+    };
+
+    let mut stats = PublisherStats::default();
+    let mut content = vec![0u8; *settings.sizes.iter().max().unwrap()];
+    let mut next_send = time::precise_time_ns();
+    let mut i = id;
+
+    while !stop.load(Ordering::Relaxed) {
+        if interval > 0 {
+            let now = time::precise_time_ns();
+            if now < next_send {
+                thread::sleep(Duration::from_nanos(next_send - now));
+            }
+            next_send += interval;
+        }
+        let size = settings.sizes[i % settings.sizes.len()];
+        let topic = &topics[i % topics.len()];
+        let stamp = time::precise_time_ns().to_be_bytes();
+        content[..TIMESTAMP_SZ].copy_from_slice(&stamp);
+
+        let message = protocol::notify_message(topic, &content[..size]);
+        stream.write_all(&message)?;
+        stats.sent += 1;
+        stats.bytes += message.len() as u64;
+        i += 1;
+    }
+    Ok(stats)
+}
+
+/// subscribes to every topic and records the latency of each NOTIFICATION received
+fn subscribe (settings: Arc<Settings>, ready: Arc<AtomicBool>, stop: Arc<AtomicBool>) -> io::Result<SubscriberStats> {
+    let mut stream = TcpStream::connect(&settings.addr[..])?;
+    for i in 0..settings.topics {
+        stream.write_all(&protocol::subscribe_message(&topic_name(i)))?;
+    }
+    ready.store(true, Ordering::SeqCst);
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    let mut stats = SubscriberStats { received: 0, bytes: 0, latency: Histogram::new() };
+    // frames may straddle reads, so keep whatever is left over in {buffer}
+    let mut buffer = Vec::with_capacity(4 * MAX_STATIC_SZ);
+    let mut chunk = [0u8; 16 * 1024];
+    let mut last_message = time::precise_time_ns();
+
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => return Err(e)
+        }
+
+        let mut consumed = 0;
+        while buffer.len() - consumed >= PREAMBLE_SZ {
+            let frame = &buffer[consumed..];
+            let length = PREAMBLE_SZ + u8_2_to_usize(&frame[..PREAMBLE_LEN_SZ]);
+            if frame.len() < length {
+                break;
+            }
+            if frame[PREAMBLE_LEN_SZ] == NOTIFICATION {
+                let now = time::precise_time_ns();
+                let payload = &frame[PREAMBLE_SZ..length];
+                let content = &payload[1 + payload[0] as usize..];
+                if content.len() >= TIMESTAMP_SZ {
+                    let mut stamp = [0u8; TIMESTAMP_SZ];
+                    stamp.copy_from_slice(&content[..TIMESTAMP_SZ]);
+                    stats.latency.record(now.saturating_sub(u64::from_be_bytes(stamp)));
+                }
+                stats.received += 1;
+                stats.bytes += length as u64;
+                last_message = now;
+            }
+            consumed += length;
+        }
+        buffer.drain(..consumed);
+
+        if stop.load(Ordering::Relaxed) && time::precise_time_ns() - last_message > DRAIN_MS * 1_000_000 {
+            break;
+        }
+    }
+    Ok(stats)
+}
+
+fn parse_settings () -> Result<Settings, String> {
+    let args = env::args().collect::<Vec<_>>();
+    let mut opts = Options::new();
+
+    opts.optopt("a", "addr", "server address (default 127.0.0.1:6567)", "HOST:PORT");
+    opts.optopt("p", "publishers", "number of publishing connections (default 1)", "NUM");
+    opts.optopt("s", "subscribers", "number of subscribing connections (default 1)", "NUM");
+    opts.optopt("t", "topics", "number of topics to spread messages over (default 1)", "NUM");
+    opts.optopt("z", "sizes", "comma separated payload sizes in bytes, cycled through (default 2000)", "BYTES,..");
+    opts.optopt("r", "rate", "messages per second per publisher, 0 is unlimited (default 0)", "NUM");
+    opts.optopt("d", "duration", "seconds to publish for (default 10)", "SECS");
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = opts.parse(&args[1..]).map_err(|f| f.to_string())?;
+    if matches.opt_present("h") {
+        return Err(opts.usage(&format!("Usage: {} [options]", args[0])));
+    }
+
+    fn num <T: std::str::FromStr> (matches: &getopts::Matches, name: &str, default: T) -> Result<T, String> {
+        match matches.opt_str(name) {
+            Some(s) => s.parse::<T>().map_err(|_| format!("invalid value for --{}: {}", name, s)),
+            None => Ok(default)
+        }
+    }
+
+    let sizes = match matches.opt_str("sizes") {
+        Some(s) => s.split(',')
+                    .map(|e| e.trim().parse::<usize>().map_err(|_| format!("invalid payload size: {}", e)))
+                    .collect::<Result<Vec<_>, _>>()?,
+        None => vec![2000]
+    };
+
+    let settings = Settings {
+        addr: matches.opt_str("addr").unwrap_or_else(|| "127.0.0.1:6567".to_owned()),
+        publishers: num(&matches, "publishers", 1)?,
+        subscribers: num(&matches, "subscribers", 1)?,
+        topics: num(&matches, "topics", 1)?,
+        sizes,
+        rate: num(&matches, "rate", 0)?,
+        duration: num(&matches, "duration", 10)?
+    };
+
+    if settings.topics == 0 || settings.publishers == 0 {
+        return Err("need at least one topic and one publisher".to_owned());
+    }
+    // the largest topic is "bench.{topics - 1}", which has to fit in the frame alongside the payload
+    let max_content = MAX_STATIC_SZ - PREAMBLE_SZ - 1 - topic_name(settings.topics - 1).len();
+    if let Some(s) = settings.sizes.iter().find(|&&s| s < TIMESTAMP_SZ || s > max_content) {
+        return Err(format!("payload size {} must be between {} and {}", s, TIMESTAMP_SZ, max_content));
+    }
+    Ok(settings)
+}
+
+fn main () {
+    let settings = match parse_settings() {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let subscribers = (0..settings.subscribers).map(|_| {
+        let ready = Arc::new(AtomicBool::new(false));
+        let (s, r, st) = (settings.clone(), ready.clone(), stop.clone());
+        (ready, thread::spawn(move || subscribe(s, r, st)))
+    }).collect::<Vec<_>>();
+
+    // the server gives no acknowledgement for SUBSCRIBE, so give it a moment to register them
+    while subscribers.iter().any(|(ready, h)| !ready.load(Ordering::SeqCst) && !h.is_finished()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    eprintln!("publishing for {} seconds with {} publishers, {} subscribers over {} topics",
+              settings.duration, settings.publishers, settings.subscribers, settings.topics);
+
+    let start = time::precise_time_ns();
+    let publishers = (0..settings.publishers).map(|i| {
+        let (s, st) = (settings.clone(), stop.clone());
+        thread::spawn(move || publish(s, i, st))
+    }).collect::<Vec<_>>();
+
+    thread::sleep(Duration::from_secs(settings.duration));
+    stop.store(true, Ordering::SeqCst);
+
+    let mut sent = PublisherStats::default();
+    for handle in publishers {
+        match handle.join().unwrap() {
+            Ok(s) => { sent.sent += s.sent; sent.bytes += s.bytes; }
+            Err(e) => eprintln!("publisher failed: {}", e)
+        }
+    }
+    let publish_secs = (time::precise_time_ns() - start) as f64 / 1_000_000_000.0;
+
+    let mut received = SubscriberStats { received: 0, bytes: 0, latency: Histogram::new() };
+    for (_, handle) in subscribers {
+        match handle.join().unwrap() {
+            Ok(s) => {
+                received.received += s.received;
+                received.bytes += s.bytes;
+                received.latency.merge(&s.latency);
+            }
+            Err(e) => eprintln!("subscriber failed: {}", e)
+        }
+    }
+
+    let lat = &received.latency;
+    let sizes = settings.sizes.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(",");
+    println!("{{\"publishers\":{},\"subscribers\":{},\"topics\":{},\"sizes\":[{}],\"rate\":{},\
+              \"duration_secs\":{:.3},\"sent\":{},\"sent_bytes\":{},\"received\":{},\"received_bytes\":{},\
+              \"send_msgs_per_sec\":{:.1},\"recv_msgs_per_sec\":{:.1},\"recv_bytes_per_sec\":{:.1},\
+              \"latency_ns\":{{\"min\":{},\"mean\":{:.1},\"p50\":{},\"p99\":{},\"p999\":{},\"max\":{}}}}}",
+             settings.publishers, settings.subscribers, settings.topics, sizes, settings.rate,
+             publish_secs, sent.sent, sent.bytes, received.received, received.bytes,
+             sent.sent as f64 / publish_secs, received.received as f64 / publish_secs,
+             received.bytes as f64 / publish_secs,
+             if lat.total == 0 { 0 } else { lat.min }, lat.mean(),
+             lat.percentile(0.5), lat.percentile(0.99), lat.percentile(0.999), lat.max);
+}
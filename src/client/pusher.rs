@@ -4,7 +4,6 @@ extern crate rqueue;
 use std::net::{TcpStream};
 use std::io::{Write};
 use rqueue::protocol;
-use std::mem;
 
 use std::thread;
 
@@ -40,7 +39,7 @@ fn main () {
                         break;
                     }
                 },
-                e => {
+                _e => {
                     //println!("err writing: {:?}", e)
                 }
             };
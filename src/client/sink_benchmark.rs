@@ -5,13 +5,11 @@ use std::net::{TcpStream};
 use std::io::{Read, Write};
 use rqueue::protocol;
 use rqueue::protocol::{u8_2_to_usize, MAX_STATIC_SZ, PREAMBLE_SZ, PREAMBLE_LEN_SZ};
-use std::mem;
 
 pub fn get_message (socket: &mut TcpStream) -> Option<([u8; MAX_STATIC_SZ], usize)>{
-    let mut message_raw: [u8; MAX_STATIC_SZ] = unsafe{ mem::uninitialized() };
+    let mut message_raw = [0u8; MAX_STATIC_SZ];
     let mut preamble_read = 0;
     let payl_size;
-    let m_type;
 
     loop {
         match socket.read(&mut message_raw[preamble_read..PREAMBLE_SZ]) {
@@ -20,7 +18,6 @@ pub fn get_message (socket: &mut TcpStream) -> Option<([u8; MAX_STATIC_SZ], usiz
                 preamble_read += num_read;
                 if preamble_read == PREAMBLE_SZ {
                     payl_size = u8_2_to_usize(&message_raw[0..PREAMBLE_LEN_SZ]);
-                    m_type = message_raw[PREAMBLE_LEN_SZ];
                     break;
                 } else {
                     println!("only read: {}", num_read);
@@ -34,7 +31,7 @@ pub fn get_message (socket: &mut TcpStream) -> Option<([u8; MAX_STATIC_SZ], usiz
     let mut curr_index = 0;
 
     {
-        let mut payload = &mut message_raw[PREAMBLE_SZ..(payl_size + PREAMBLE_SZ)];
+        let payload = &mut message_raw[PREAMBLE_SZ..(payl_size + PREAMBLE_SZ)];
         loop { //this loop might be bad for the event loop. might be able to abstract into a
                //coroutine powered by mio
             match socket.read(&mut payload[curr_index..]) {
@@ -57,7 +54,7 @@ pub fn get_message (socket: &mut TcpStream) -> Option<([u8; MAX_STATIC_SZ], usiz
 
     }
 
-    return Some((message_raw, payl_size + PREAMBLE_SZ))
+    Some((message_raw, payl_size + PREAMBLE_SZ))
 }
 
 fn main () {
@@ -74,16 +71,13 @@ fn main () {
         let start = time::precise_time_ns();
 
         loop {
-            match get_message(&mut stream) {
-                Some((payload, bytes_read)) => {
-                    bytes += bytes_read;
-                    count += 1;
-                    let topic_len = payload[0] as usize;
-                    let topic = &payload[1..topic_len+1];
-
-                    let message = &payload[5..bytes_read];
-                },
-                _ => ()
+            if let Some((payload, bytes_read)) = get_message(&mut stream) {
+                bytes += bytes_read;
+                count += 1;
+                let topic_len = payload[0] as usize;
+                let _topic = &payload[1..topic_len+1];
+
+                let _message = &payload[5..bytes_read];
             };
 
             if count % 10000 == 0 {
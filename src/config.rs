@@ -0,0 +1,462 @@
+use std::{fmt, fs, io};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use serde::Deserialize;
+use toml;
+use protocol::{MAX_PAYLOAD_SZ, MAX_STATIC_SZ};
+use transport::ListenAddr;
+use tls::TlsConfig;
+use auth::Credentials;
+use acl::Acl;
+use limits::RateLimit;
+use slice_map::Hashing;
+use logging::{self, Logger, Format};
+use server::{ServerBuilder, ListenerConfig, DEFAULT_PORT, DEFAULT_WORKERS, DEFAULT_DRAIN_TIMEOUT, DEFAULT_SYS_INTERVAL};
+use threadpool::DEFAULT_CAPACITY;
+
+/// server configuration, usually read from a TOML file. every field has a default so an empty
+/// file is a valid configuration
+///
+/// ```toml
+/// [server]
+/// listen = "0.0.0.0:6567"  # shorthand for a single listener with the server wide limits
+/// workers = 8
+/// queue_depth = 1024       # messages per worker before publishers are paused
+/// drain_timeout = 5        # seconds
+/// notify_shutdown = false
+/// sys_interval = 10        # seconds between $SYS statistics, 0 turns them off
+/// topic_hash = "keyed"     # or "fast" when clients are trusted
+///
+/// [limits]
+/// max_connections = 10000
+/// max_payload = 2045       # bytes, excluding the preamble
+/// max_subscriptions = 1000 # per client
+/// messages_per_sec = 500   # per client, unlimited when left out
+/// bytes_per_sec = 1048576  # per client, preambles included
+///
+/// [auth]
+/// credentials = "users.toml"
+/// required = true          # the default once there are credentials
+/// acl = "acl.toml"         # reloaded on SIGHUP
+///
+/// [metrics]
+/// listen = "127.0.0.1:9100"  # Prometheus scrapes /metrics here
+///
+/// [log]
+/// level = "info"           # off, error, warn, info, debug or trace
+/// format = "plain"         # or json
+///
+/// # instead of server.listen, any number of listeners with their own limits
+/// [[listener]]
+/// address = "[::]:6567"
+/// max_connections = 5000
+///
+/// [[listener]]
+/// address = "127.0.0.1:6568"
+///
+/// [[listener]]
+/// address = "unix:/run/rqueue.sock"
+/// require_auth = false
+///
+/// [[listener]]
+/// address = "0.0.0.0:6568"
+/// tls = { cert = "server.pem", key = "server.key", client_ca = "clients.pem" }
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub limits: LimitsSection,
+    pub auth: AuthSection,
+    pub metrics: MetricsSection,
+    pub persistence: Option<PersistenceSection>,
+    pub log: LogSection,
+    pub listener: Vec<ListenerSection>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub listen: Option<String>,
+    pub workers: usize,
+    pub queue_depth: usize,
+    pub drain_timeout: u64,
+    pub notify_shutdown: bool,
+    pub sys_interval: u64,
+    pub topic_hash: String
+}
+
+impl Default for ServerSection {
+    fn default() -> ServerSection {
+        ServerSection {
+            listen: None,
+            workers: DEFAULT_WORKERS,
+            queue_depth: DEFAULT_CAPACITY,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT.as_secs(),
+            notify_shutdown: false,
+            sys_interval: DEFAULT_SYS_INTERVAL.as_secs(),
+            topic_hash: "keyed".to_owned()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub max_connections: usize,
+    pub max_payload: usize,
+    pub max_subscriptions: usize,
+    pub messages_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u32>
+}
+
+impl Default for LimitsSection {
+    fn default() -> LimitsSection {
+        LimitsSection {
+            max_connections: usize::MAX,
+            max_payload: MAX_PAYLOAD_SZ,
+            max_subscriptions: usize::MAX,
+            messages_per_sec: None,
+            bytes_per_sec: None
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    /// path to the credentials file, see auth::Credentials
+    pub credentials: Option<String>,
+
+    /// defaults to true once there are credentials
+    pub required: Option<bool>,
+
+    /// path to the ACL file, see acl::Acl. reloaded on SIGHUP
+    pub acl: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    /// where to serve metrics over HTTP, not served when unset
+    pub listen: Option<String>
+}
+
+/// where durable state would be kept. notifications are only ever held in memory for now, so
+/// the section is rejected rather than accepted and ignored
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceSection {
+    pub dir: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    pub level: String,
+    pub format: String
+}
+
+impl Default for LogSection {
+    fn default() -> LogSection {
+        LogSection {
+            level: "info".to_owned(),
+            format: Format::Plain.to_string()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSection {
+    pub address: String,
+    pub max_connections: Option<usize>,
+    pub max_payload: Option<usize>,
+    pub tls: Option<TlsSection>,
+    pub require_auth: Option<bool>
+}
+
+/// certificate paths for a TLS listener, PEM encoded
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: String,
+    pub key: String,
+
+    /// require clients to present a certificate signed by this CA
+    pub client_ca: Option<String>
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// the file could not be read
+    Io(String, io::Error),
+
+    /// the file is not valid TOML, or does not match the expected layout
+    Parse(toml::de::Error),
+
+    /// a setting has a value that the server can't run with
+    Invalid(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(f, "could not read {}: {}", path, e),
+            ConfigError::Parse(ref e) => write!(f, "invalid config: {}", e),
+            ConfigError::Invalid(ref msg) => write!(f, "invalid config: {}", msg)
+        }
+    }
+}
+
+impl Config {
+    /// reads and validates a config file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(path.display().to_string(), e))?;
+        Config::parse(&contents)
+    }
+
+    /// parses and validates the contents of a config file
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// checks that every setting is usable. called when parsing, call it again after changing
+    /// fields (e.g. with command line overrides)
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.listen.is_some() && !self.listener.is_empty() {
+            return Err(ConfigError::Invalid("use either server.listen or [[listener]] tables, not both".to_owned()));
+        }
+        for listener in self.listeners()? {
+            if listener.max_connections == Some(0) {
+                return Err(ConfigError::Invalid(format!("listener {}: max_connections must be at least 1", listener.addr)));
+            }
+            if let Some(p) = listener.max_payload {
+                if p == 0 || p > MAX_PAYLOAD_SZ {
+                    return Err(ConfigError::Invalid(format!("listener {}: max_payload must be between 1 and {}, got {}",
+                                                            listener.addr, MAX_PAYLOAD_SZ, p)));
+                }
+            }
+        }
+        if self.persistence.is_some() {
+            return Err(ConfigError::Invalid("[persistence] is not supported yet, notifications are only held in memory".to_owned()));
+        }
+        self.metrics_addr()?;
+        self.logger()?;
+        self.topic_hashing()?;
+        if self.server.workers == 0 {
+            return Err(ConfigError::Invalid("server.workers must be at least 1".to_owned()));
+        }
+        if self.server.queue_depth == 0 {
+            return Err(ConfigError::Invalid("server.queue_depth must be at least 1".to_owned()));
+        }
+        if self.limits.max_connections == 0 {
+            return Err(ConfigError::Invalid("limits.max_connections must be at least 1".to_owned()));
+        }
+        if self.limits.max_payload == 0 || self.limits.max_payload > MAX_PAYLOAD_SZ {
+            return Err(ConfigError::Invalid(format!("limits.max_payload must be between 1 and {}, got {}",
+                                                    MAX_PAYLOAD_SZ, self.limits.max_payload)));
+        }
+        if self.limits.max_subscriptions == 0 {
+            return Err(ConfigError::Invalid("limits.max_subscriptions must be at least 1".to_owned()));
+        }
+        if self.limits.messages_per_sec == Some(0) {
+            return Err(ConfigError::Invalid("limits.messages_per_sec must be at least 1".to_owned()));
+        }
+        // a full frame has to fit in the bucket or large messages would never get through
+        if let Some(rate) = self.limits.bytes_per_sec {
+            if (rate as usize) < MAX_STATIC_SZ {
+                return Err(ConfigError::Invalid(format!("limits.bytes_per_sec must be at least {}, got {}",
+                                                        MAX_STATIC_SZ, rate)));
+            }
+        }
+        Ok(())
+    }
+
+    /// every listener that is configured, falling back to 0.0.0.0:DEFAULT_PORT
+    pub fn listeners(&self) -> Result<Vec<ListenerConfig>, ConfigError> {
+        if let Some(ref listen) = self.server.listen {
+            return Ok(vec![ListenerConfig::new(parse_addr("server.listen", listen)?)]);
+        }
+        if self.listener.is_empty() {
+            return Ok(vec![ListenerConfig::new(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)))]);
+        }
+        self.listener.iter().map(|l| {
+            Ok(ListenerConfig {
+                addr: parse_addr("listener.address", &l.address)?,
+                max_connections: l.max_connections,
+                max_payload: l.max_payload,
+                tls: l.tls.as_ref().map(|t| TlsConfig {
+                    cert: t.cert.clone().into(),
+                    key: t.key.clone().into(),
+                    client_ca: t.client_ca.clone().map(|ca| ca.into())
+                }),
+                require_auth: l.require_auth
+            })
+        }).collect()
+    }
+
+    /// where metrics are served, if anywhere
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>, ConfigError> {
+        match self.metrics.listen {
+            Some(ref addr) => addr.parse().map(Some)
+                .map_err(|e| ConfigError::Invalid(format!("metrics.listen: {}", e))),
+            None => Ok(None)
+        }
+    }
+
+    /// a logger as configured, to be installed with Logger::init
+    pub fn logger(&self) -> Result<Logger, ConfigError> {
+        let level = logging::parse_level(&self.log.level).map_err(|e| ConfigError::Invalid(format!("log.level: {}", e)))?;
+        let format = self.log.format.parse().map_err(|e| ConfigError::Invalid(format!("log.format: {}", e)))?;
+        Ok(Logger::new(level, format))
+    }
+
+    /// how topics are hashed, with fresh keys when keyed
+    pub fn topic_hashing(&self) -> Result<Hashing, ConfigError> {
+        self.server.topic_hash.parse().map_err(|e| ConfigError::Invalid(format!("server.topic_hash: {}", e)))
+    }
+
+    /// a ServerBuilder with every setting applied
+    pub fn builder(&self) -> Result<ServerBuilder, ConfigError> {
+        self.validate()?;
+        let mut builder = self.listeners()?.into_iter().fold(ServerBuilder::default(), |b, l| b.listen(l));
+        if let Some(ref path) = self.auth.credentials {
+            builder = builder.credentials(Credentials::from_file(path)?);
+        }
+        if let Some(required) = self.auth.required {
+            builder = builder.require_auth(required);
+        }
+        if let Some(ref path) = self.auth.acl {
+            builder = builder.acl(Acl::from_file(path)?);
+        }
+        if let Some(addr) = self.metrics_addr()? {
+            builder = builder.metrics(addr);
+        }
+        Ok(builder
+            .workers(self.server.workers)
+            .queue_depth(self.server.queue_depth)
+            .drain_timeout(Duration::from_secs(self.server.drain_timeout))
+            .notify_shutdown(self.server.notify_shutdown)
+            .sys_interval(Duration::from_secs(self.server.sys_interval))
+            .topic_hashing(self.topic_hashing()?)
+            .max_connections(self.limits.max_connections)
+            .max_payload(self.limits.max_payload)
+            .max_subscriptions(self.limits.max_subscriptions)
+            .rate_limit(RateLimit {
+                messages_per_sec: self.limits.messages_per_sec,
+                bytes_per_sec: self.limits.bytes_per_sec
+            }))
+    }
+}
+
+fn parse_addr(setting: &str, addr: &str) -> Result<ListenAddr, ConfigError> {
+    addr.parse().map_err(|e| ConfigError::Invalid(format!("{}: {}", setting, e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, ConfigError};
+    use transport::ListenAddr;
+    use tls::TlsConfig;
+
+    #[test]
+    fn empty_file_is_the_default() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.listeners().unwrap()[0].addr.to_string(), "0.0.0.0:6567");
+        assert_eq!(config.server.workers, 8);
+    }
+
+    #[test]
+    fn reads_every_section() {
+        let config = Config::parse("[server]\nlisten = \"127.0.0.1:5000\"\nworkers = 2\nqueue_depth = 16\ntopic_hash = \"fast\"\n\
+                                    [limits]\nmax_connections = 10\nmax_payload = 512\n").unwrap();
+        assert_eq!(config.listeners().unwrap()[0].addr.tcp().unwrap().port(), 5000);
+        assert_eq!(config.server.workers, 2);
+        assert_eq!(config.server.queue_depth, 16);
+        assert_eq!(config.topic_hashing().unwrap().to_string(), "fast");
+        assert_eq!(config.limits.max_connections, 10);
+        assert_eq!(config.limits.max_payload, 512);
+    }
+
+    #[test]
+    fn reads_client_limits() {
+        let config = Config::parse("[limits]
+max_subscriptions = 100
+messages_per_sec = 50
+").unwrap();
+        assert_eq!(config.limits.max_subscriptions, 100);
+        assert_eq!(config.limits.messages_per_sec, Some(50));
+        assert_eq!(config.limits.bytes_per_sec, None);
+        assert!(config.builder().is_ok());
+    }
+
+    #[test]
+    fn reads_multiple_listeners() {
+        let config = Config::parse("[[listener]]\naddress = \"[::1]:6567\"\nmax_connections = 5\n\
+                                    [[listener]]\naddress = \"127.0.0.1:6568\"\nmax_payload = 100\n").unwrap();
+        let listeners = config.listeners().unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners[0].addr.tcp().unwrap().is_ipv6());
+        assert_eq!(listeners[0].max_connections, Some(5));
+        assert_eq!(listeners[1].max_payload, Some(100));
+    }
+
+    #[test]
+    fn reads_unix_listeners() {
+        let config = Config::parse("[server]\nlisten = \"unix:/tmp/rqueue.sock\"\n").unwrap();
+        assert_eq!(config.listeners().unwrap()[0].addr, ListenAddr::Unix("/tmp/rqueue.sock".into()));
+    }
+
+    #[test]
+    fn reads_tls_listeners() {
+        let config = Config::parse("[[listener]]\naddress = \"0.0.0.0:6568\"\n\
+                                    tls = { cert = \"a.pem\", key = \"a.key\" }\n").unwrap();
+        let tls = config.listeners().unwrap()[0].tls.clone().unwrap();
+        assert_eq!(tls, TlsConfig::new("a.pem", "a.key"));
+    }
+
+    #[test]
+    fn reads_the_metrics_address() {
+        let config = Config::parse("[metrics]\nlisten = \"127.0.0.1:9100\"\n").unwrap();
+        assert_eq!(config.metrics_addr().unwrap().unwrap().port(), 9100);
+        assert_eq!(Config::parse("").unwrap().metrics_addr().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        match Config::parse("[server]\nworkerz = 2\n") {
+            Err(ConfigError::Parse(_)) => (),
+            other => panic!("expected a parse error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for contents in &["[server]\nworkers = 0\n",
+                          "[server]\nqueue_depth = 0\n",
+                          "[server]\nlisten = \"localhost\"\n",
+                          "[server]\nlisten = \"unix:\"\n",
+                          "[limits]\nmax_payload = 4096\n",
+                          "[limits]\nmax_subscriptions = 0\n",
+                          "[limits]\nmessages_per_sec = 0\n",
+                          "[limits]\nbytes_per_sec = 100\n",
+                          "[metrics]\nlisten = \"unix:/tmp/metrics\"\n",
+                          "[persistence]\n",
+                          "[persistence]\ndir = \"/var/lib/rqueue\"\n",
+                          "[log]\nlevel = \"loud\"\n",
+                          "[log]\nformat = \"xml\"\n",
+                          "[server]\ntopic_hash = \"md5\"\n",
+                          "[server]\nlisten = \"0.0.0.0:1\"\n[[listener]]\naddress = \"0.0.0.0:2\"\n"] {
+            match Config::parse(contents) {
+                Err(ConfigError::Invalid(_)) => (),
+                other => panic!("expected {:?} to be invalid, got {:?}", contents, other)
+            }
+        }
+    }
+}
@@ -1,10 +1,33 @@
 extern crate mio;
 extern crate net2;
+extern crate libc;
+extern crate serde;
+extern crate toml;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate bcrypt;
+extern crate sha2;
+extern crate time;
+#[macro_use]
+extern crate log;
 
 pub mod slice_map;
+pub mod topics;
 pub mod threadpool;
 pub mod rpc;
 pub mod protocol;
+pub mod server;
+pub mod signal;
+pub mod config;
+pub mod transport;
+pub mod client;
+pub mod tls;
+pub mod auth;
+pub mod acl;
+pub mod metrics;
+pub mod admin;
+pub mod logging;
+pub mod limits;
 
 #[test]
 fn it_works() {
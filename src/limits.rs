@@ -0,0 +1,155 @@
+use std::time::Instant;
+
+/// per client rate limits. each client gets a bucket per limit holding up to one second's worth,
+/// so short bursts are let through as long as the average stays under the rate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// messages a client may send per second, of any type. 0 lets nothing through
+    pub messages_per_sec: Option<u32>,
+
+    /// bytes a client may send per second, preambles included. 0 lets nothing through
+    pub bytes_per_sec: Option<u32>
+}
+
+/// which limit a client ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Connections,
+    Subscriptions,
+    Messages,
+    Bytes
+}
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Limit::Connections => "connections",
+            Limit::Subscriptions => "subscriptions",
+            Limit::Messages => "messages",
+            Limit::Bytes => "bytes"
+        }
+    }
+}
+
+/// a token bucket that refills continuously at {rate} tokens per second, up to {capacity}
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    /// a full bucket
+    pub fn new(rate: u32, capacity: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last: now
+        }
+    }
+
+    /// takes {n} tokens if there are that many. requests larger than the capacity are let through
+    /// once the bucket is full, so a single large message can't be starved forever. a bucket
+    /// without capacity never fills, and lets nothing through
+    pub fn take(&mut self, n: u32, now: Instant) -> bool {
+        if self.capacity == 0.0 {
+            return false;
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        let n = (n as f64).min(self.capacity);
+        if self.tokens < n {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+}
+
+/// the buckets of one client
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit, now: Instant) -> RateLimiter {
+        RateLimiter {
+            messages: limit.messages_per_sec.map(|rate| TokenBucket::new(rate, rate, now)),
+            bytes: limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, rate, now))
+        }
+    }
+
+    /// checks a message of {bytes} against the limits, the limit that was hit if any. a message
+    /// that is turned away by the byte limit still counts against the message limit
+    pub fn admit(&mut self, bytes: usize, now: Instant) -> Result<(), Limit> {
+        if let Some(ref mut messages) = self.messages {
+            if !messages.take(1, now) {
+                return Err(Limit::Messages);
+            }
+        }
+        if let Some(ref mut bucket) = self.bytes {
+            if !bucket.take(bytes as u32, now) {
+                return Err(Limit::Bytes);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TokenBucket, RateLimit, RateLimiter, Limit};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn buckets_allow_bursts_then_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, 10, start);
+        assert_eq!((0..15).filter(|_| bucket.take(1, start)).count(), 10);
+        assert!(!bucket.take(1, start + Duration::from_millis(50)));
+        assert!(bucket.take(1, start + Duration::from_millis(100)));
+        // never more than the capacity, however long it has been
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..15).filter(|_| bucket.take(1, later)).count(), 10);
+    }
+
+    #[test]
+    fn oversized_requests_need_a_full_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 100, start);
+        assert!(bucket.take(500, start));
+        assert!(!bucket.take(500, start + Duration::from_millis(500)));
+        assert!(bucket.take(500, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn limiters_report_which_limit_was_hit() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(&RateLimit { messages_per_sec: Some(2), bytes_per_sec: Some(100) }, start);
+        assert_eq!(limiter.admit(60, start), Ok(()));
+        assert_eq!(limiter.admit(60, start), Err(Limit::Bytes));
+        assert_eq!(limiter.admit(10, start), Err(Limit::Messages));
+
+        let mut unlimited = RateLimiter::new(&RateLimit::default(), start);
+        assert!((0..1000).all(|_| unlimited.admit(2048, start).is_ok()));
+    }
+
+    #[test]
+    fn zero_rates_let_nothing_through() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(0, 0, start);
+        assert!(!bucket.take(0, start));
+        assert!(!bucket.take(1, start + Duration::from_secs(60)));
+
+        let mut silenced = RateLimiter::new(&RateLimit { messages_per_sec: Some(0), bytes_per_sec: None }, start);
+        assert_eq!(silenced.admit(10, start), Err(Limit::Messages));
+        let mut starved = RateLimiter::new(&RateLimit { messages_per_sec: None, bytes_per_sec: Some(0) }, start);
+        assert_eq!(starved.admit(10, start + Duration::from_secs(60)), Err(Limit::Bytes));
+    }
+}
@@ -0,0 +1,188 @@
+use std::{fmt, io};
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::str::FromStr;
+use log::{self, Log, LevelFilter, Metadata, Record, SetLoggerError};
+use log::kv::{self, Key, Value, VisitSource};
+use time;
+
+/// how log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `2016-01-02T03:04:05.678Z INFO  rqueue::server: accepted conn=9 addr=127.0.0.1:5555`
+    Plain,
+
+    /// one JSON object per line, with the fields next to ts, level, target and msg
+    Json
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {:?}, expected plain or json", s))
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Format::Plain => "plain",
+            Format::Json => "json"
+        })
+    }
+}
+
+/// writes records to stderr, one line each. records above the level are filtered by the log
+/// macros before their arguments are evaluated, so disabled logging costs a single comparison
+pub struct Logger {
+    level: LevelFilter,
+    format: Format
+}
+
+impl Logger {
+    pub fn new(level: LevelFilter, format: Format) -> Logger {
+        Logger { level, format }
+    }
+
+    /// installs the logger for the whole process. fails if a logger is already installed
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    /// the line for {record}, without the newline
+    fn format(&self, record: &Record) -> String {
+        let now = time::now_utc();
+        let ts = format!("{}.{:03}Z", now.strftime("%Y-%m-%dT%H:%M:%S").expect("static format"),
+                         now.tm_nsec / 1_000_000);
+        let mut line = String::new();
+        match self.format {
+            Format::Plain => {
+                let _ = write!(line, "{} {:<5} {}: {}", ts, record.level(), record.target(), record.args());
+                let _ = record.key_values().visit(&mut PlainFields(&mut line));
+            }
+            Format::Json => {
+                line.push_str("{\"ts\":");
+                json_string(&mut line, &ts);
+                line.push_str(",\"level\":");
+                json_string(&mut line, record.level().as_str());
+                line.push_str(",\"target\":");
+                json_string(&mut line, record.target());
+                line.push_str(",\"msg\":");
+                json_string(&mut line, &record.args().to_string());
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                line.push('}');
+            }
+        }
+        line
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = self.format(record);
+        line.push('\n');
+        // a single write so that lines from different threads don't interleave
+        let _ = io::stderr().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// parses a level name as used on the command line and in the config file
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse().map_err(|_| format!("unknown log level {:?}, expected off, error, warn, info, debug or trace", level))
+}
+
+struct PlainFields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for PlainFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
+
+struct JsonFields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for JsonFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        json_string(self.0, key.as_str());
+        self.0.push(':');
+        if let Some(n) = value.to_u64() {
+            let _ = write!(self.0, "{}", n);
+        } else if let Some(n) = value.to_i64() {
+            let _ = write!(self.0, "{}", n);
+        } else if let Some(b) = value.to_bool() {
+            let _ = write!(self.0, "{}", b);
+        } else {
+            json_string(self.0, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// appends {s} as a quoted JSON string
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Logger, Format};
+    use log::{Level, LevelFilter, Record};
+
+    fn line(format: Format) -> String {
+        let fields: &[(&str, &str)] = &[("addr", "127.0.0.1:5555"), ("topic", "a\"b")];
+        let logger = Logger::new(LevelFilter::Info, format);
+        logger.format(&Record::builder()
+            .level(Level::Info)
+            .target("rqueue::server")
+            .args(format_args!("accepted"))
+            .key_values(&fields)
+            .build())
+    }
+
+    #[test]
+    fn plain_lines_end_with_the_fields() {
+        let line = line(Format::Plain);
+        assert!(line.ends_with(" INFO  rqueue::server: accepted addr=127.0.0.1:5555 topic=a\"b"), "{}", line);
+    }
+
+    #[test]
+    fn json_lines_are_escaped_objects() {
+        let line = line(Format::Json);
+        assert!(line.starts_with("{\"ts\":\""), "{}", line);
+        assert!(line.ends_with(",\"level\":\"INFO\",\"target\":\"rqueue::server\",\"msg\":\"accepted\",\
+                                \"addr\":\"127.0.0.1:5555\",\"topic\":\"a\\\"b\"}"), "{}", line);
+    }
+}
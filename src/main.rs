@@ -1,94 +1,44 @@
-extern crate mio;
 extern crate rqueue;
 extern crate getopts;
-
-use std::{mem, env};
-use std::collections::{HashMap};
-use std::net::SocketAddr;
-use mio::tcp::{TcpStream, TcpListener};
-use mio::{Token, EventSet, EventLoop, PollOpt, Handler};
-use getopts::Options;
-use rqueue::protocol::RawMessage;
-use rqueue::threadpool::{StatePool, QueuePoolWorker, PoolWorker};
-use rqueue::protocol::DEREGISTER_ONCE;
-
-const SERVER: mio::Token = mio::Token(0);
-
-struct RQueueServer {
-    server: TcpListener,
-    clients: HashMap<Token, Client>, // just a regular slow hm for now
-    token_counter: usize,
-    worker_pool: StatePool<RawMessage, ()>
-}
-
-// implements a vanilla-ish mio event loop
-impl Handler for RQueueServer {
-    type Timeout = ();
-    type Message = ();
-
-    fn ready(&mut self, event_loop: &mut EventLoop<RQueueServer>, token: Token, events: EventSet) {
-        match token {
-            SERVER => {
-                assert!(events.is_readable());
-                let client_socket = match self.server.accept() {
-                    Ok(Some((socket, _))) => socket,
-                    Ok(None) => unreachable!(),
-                    Err(e) => {
-                        println!("listener.accept() errored: {}", e);
-                        return;
-                    }
-                };
-                //for now just increment the token (FAIP the client id)
-                self.token_counter += 1;
-                let ntoken = Token(self.token_counter);
-                println!("new token {:?}", ntoken);
-                self.clients.insert(ntoken, Client::new(client_socket));
-
-                event_loop.register(&self.clients[&ntoken].socket, ntoken, EventSet::readable(),
-                                    PollOpt::edge()).unwrap();
-            }
-            token => {
-                if events.is_hup() { // on client hangup
-                    println!("removing token {:?}", token);
-                    let client = self.clients.remove(&token).unwrap();
-                    client.disconnect(&mut self.worker_pool);
-                } else {
-                    let mut client = self.clients.get_mut(&token).unwrap();
-                    self.worker_pool.handle_messages(&mut client.socket);
-                    let _ = event_loop.reregister(&client.socket, token, EventSet::readable(), PollOpt::edge());
-                }
-            }
-        }
-    }
+#[macro_use]
+extern crate log;
+
+use std::{env, io, process};
+use std::str::FromStr;
+use getopts::{Options, Matches};
+use rqueue::auth;
+use rqueue::server::ServerHandle;
+use rqueue::config::{Config, ListenerSection};
+use rqueue::acl::Acl;
+use rqueue::signal::{SignalSet, SIGINT, SIGTERM, SIGHUP};
+use rqueue::transport::ListenAddr;
+
+/// prints {msg} and exits with a usage error
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(2);
 }
 
-/// wrapper over client sockets
-struct Client {
-    socket: TcpStream,
-    socket_addr: SocketAddr
+/// parses the value of an option if it was given
+fn opt<T: FromStr>(matches: &Matches, name: &str) -> Option<T> {
+    matches.opt_str(name).map(|e| {
+        e.parse::<T>().unwrap_or_else(|_| fail(&format!("invalid value for --{}: {}", name, e)))
+    })
 }
 
-impl Client {
-    fn new(socket: TcpStream) -> Client {
-        let addr = socket.peer_addr().unwrap();
-        Client {
-            socket: socket,
-            socket_addr: addr//cache this value
-        }
-    }
-    /// disconnects a client
-    fn disconnect(&self, pool: &mut StatePool<RawMessage, ()>) {
-        //sends a DEREGISTER_ONCE message to each worker
-        for sender in pool.workers.iter() {
-            let message = RawMessage {
-                m_type: DEREGISTER_ONCE,
-                length: 0,
-                socket_addr: self.socket_addr.clone(),
-                bytes: unsafe {mem::uninitialized()},
-                raw_fd: -1
-            };
-            let _ = sender.send(message);
+/// rereads the ACL file, keeping the current ACL if the file is broken
+fn reload(config: &Config, handle: &ServerHandle) {
+    let path = match config.auth.acl {
+        Some(ref path) => path,
+        None => return
+    };
+    match Acl::from_file(path) {
+        Ok(acl) => {
+            if let Err(e) = handle.set_acl(acl) {
+                error!(error:% = e; "could not reload the acl");
+            }
         }
+        Err(e) => warn!(error:% = e; "keeping the current acl")
     }
 }
 
@@ -96,47 +46,134 @@ fn main() {
     let args = env::args().collect::<Vec<_>>();
     let mut opts = Options::new();
 
+    opts.optopt("c", "config", "path to a TOML config file. other options override its values", "FILE");
+    opts.optmulti("l", "listen", "address to listen on, host:port or unix:PATH. may be given more than once, replaces the configured listeners", "ADDR");
     opts.optopt("p", "port", "tcp server port", "PORT_NUM");
     opts.optopt("t", "threads", "auxiliary worker threads", "NUM_THREADS");
+    opts.optopt("m", "max-connections", "maximum concurrent client connections", "NUM");
+    opts.optopt("d", "drain-timeout", "seconds to wait for in-flight messages on shutdown", "SECS");
+    opts.optopt("", "metrics", "serve Prometheus metrics over HTTP on this address", "ADDR");
+    opts.optopt("", "log-level", "off, error, warn, info (the default), debug or trace", "LEVEL");
+    opts.optopt("", "log-format", "plain (the default) or json", "FORMAT");
+    opts.optflag("n", "notify-shutdown", "send clients a SHUTDOWN frame before exiting");
+    opts.optflag("", "hash-password", "read a password from stdin and print its hash for the credentials file");
+    opts.optflag("", "hash-token", "read a token from stdin and print its hash for the credentials file");
+    opts.optflag("h", "help", "print this help menu");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { fail(&format!("{}\n{}", f, opts.usage("Usage: server [options]"))) }
     };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("Usage: server [options]"));
+        return;
+    }
+    if matches.opt_present("hash-password") || matches.opt_present("hash-token") {
+        let mut secret = String::new();
+        io::stdin().read_line(&mut secret).unwrap_or_else(|e| fail(&e.to_string()));
+        let secret = secret.trim_end_matches(&['\r', '\n'][..]);
+        if matches.opt_present("hash-password") {
+            println!("{}", auth::hash_password(secret));
+        } else {
+            println!("{}", auth::hash_token(secret));
+        }
+        return;
+    }
 
-    let port = match matches.opt_str("p") {
-        Some(e) => e.parse::<usize>().unwrap_or(6567),
-        _ => 6567
+    let mut config = match matches.opt_str("c") {
+        Some(path) => Config::from_file(&path).unwrap_or_else(|e| fail(&e.to_string())),
+        None => Config::default()
     };
 
-    let aux_threads = match matches.opt_str("t") {
-        Some(e) => e.parse::<usize>().unwrap_or(8),
-        _ => 8
-    };
+    let listen = matches.opt_strs("listen");
+    if !listen.is_empty() {
+        config.server.listen = None;
+        config.listener = listen.into_iter().map(|address| ListenerSection {
+            address,
+            max_connections: None,
+            max_payload: None,
+            tls: None,
+            require_auth: None
+        }).collect();
+    }
+    if let Some(port) = opt::<u16>(&matches, "port") {
+        // keeps the configured interface, only the port changes
+        let mut listeners = config.listeners().unwrap_or_else(|e| fail(&e.to_string()));
+        if listeners.len() > 1 {
+            fail("--port is ambiguous with more than one listener, use --listen instead");
+        }
+        match listeners[0].addr {
+            ListenAddr::Tcp(ref mut addr) => addr.set_port(port),
+            ListenAddr::Unix(_) => fail("--port does not apply to a unix socket listener")
+        }
+        config.server.listen = Some(listeners[0].addr.to_string());
+        config.listener.clear();
+    }
+    if let Some(threads) = opt(&matches, "threads") {
+        config.server.workers = threads;
+    }
+    if let Some(max) = opt(&matches, "max-connections") {
+        config.limits.max_connections = max;
+    }
+    if let Some(secs) = opt(&matches, "drain-timeout") {
+        config.server.drain_timeout = secs;
+    }
+    if matches.opt_present("n") {
+        config.server.notify_shutdown = true;
+    }
+    if let Some(addr) = matches.opt_str("metrics") {
+        config.metrics.listen = Some(addr);
+    }
+    if let Some(level) = matches.opt_str("log-level") {
+        config.log.level = level;
+    }
+    if let Some(format) = matches.opt_str("log-format") {
+        config.log.format = format;
+    }
+    let logger = config.logger().unwrap_or_else(|e| fail(&e.to_string()));
+    logger.init().expect("no other logger is installed");
+
+    let builder = config.builder().unwrap_or_else(|e| fail(&e.to_string()));
 
-    let address = format!("0.0.0.0:{}", port).parse().unwrap();
-    let server = TcpListener::bind(&address).unwrap();
+    // has to happen before any threads are started so that they all inherit the mask
+    let signals = SignalSet::block(&[SIGINT, SIGTERM, SIGHUP]).unwrap();
 
-    let mut event_loop = EventLoop::new().unwrap();
-    event_loop.register(&server, SERVER, EventSet::all(), PollOpt::edge()).unwrap();
+    let server = builder.build().unwrap_or_else(|e| fail(&e.to_string()));
 
-    println!("running server on port {}", port);
-    println!("   with {} workers", aux_threads);
+    for addr in server.local_addrs().unwrap() {
+        info!(addr:% = addr, workers = config.server.workers; "listening");
+    }
+    if let Some(addr) = server.metrics_addr() {
+        info!(addr:% = addr; "serving metrics at /metrics");
+    }
 
     //start the event loop
-    let _ = event_loop.run(&mut RQueueServer { server: server,
-                                               clients: HashMap::new(),
-                                               token_counter: 0,
-                                               // decoupled worker pool with configurable # of
-                                               // threads
-                                               worker_pool: StatePool::new(aux_threads, |contacts| QueuePoolWorker::new(contacts))
-    });
-}
+    let handle = server.spawn().unwrap();
+
+    loop {
+        match signals.wait() {
+            Ok(SIGHUP) => reload(&config, &handle),
+            Ok(signal) => {
+                info!(signal = signal; "received signal");
+                break;
+            }
+            Err(e) => {
+                error!(error:% = e; "waiting for signals failed");
+                break;
+            }
+        }
+    }
 
-//for debug
-/*fn to_std_tcpstream(stream: &TcpStream) -> std::net::TcpStream {
-    let builder = unsafe {
-        TcpBuilder::from_raw_fd(stream.as_raw_fd())
-    };
-    builder.to_tcp_stream().unwrap()
-}*/
+    match handle.shutdown() {
+        Ok(summary) => info!(connections_accepted = summary.connections_accepted,
+                             connections_open = summary.connections_open,
+                             messages_received = summary.messages_received,
+                             workers_drained = summary.workers_drained,
+                             workers_abandoned = summary.workers_abandoned,
+                             drain_time:? = summary.drain_time; "stopped"),
+        Err(e) => {
+            error!(error:% = e; "event loop exited with an error");
+            process::exit(1);
+        }
+    }
+}
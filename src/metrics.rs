@@ -0,0 +1,310 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use limits::Limit;
+use topics::TopicIndex;
+
+/// the largest request the exporter reads, anything longer is answered with a 400
+const MAX_REQUEST: usize = 8192;
+
+/// how long the exporter waits on a scraper that does not send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// the most topics a scrape lists subscribers for, the busiest ones. every topic is a series of
+/// its own, so this keeps a server with many topics from swamping the scraper
+pub const MAX_TOPIC_SERIES: usize = 100;
+
+/// counters and gauges shared by the event loop, the workers and the exporter. everything is
+/// updated with relaxed atomics, so a scrape may see counters from slightly different moments
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicUsize,
+    connections_accepted: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_delivered: AtomicU64,
+    bytes_delivered: AtomicU64,
+
+    /// notifications not written at all because the subscriber was gone or its socket failed
+    writes_dropped: AtomicU64,
+
+    /// notifications that failed part way through, which closes the subscriber
+    writes_partial: AtomicU64,
+
+    /// client notifications nobody was subscribed to, as reported back to the event loop
+    notifications_unrouted: AtomicU64,
+
+    /// violations, by Limit
+    limited: [AtomicU64; 4],
+
+    /// the server's subscriptions, read when scraped
+    topics: Mutex<Option<Arc<TopicIndex>>>,
+
+    /// work queued on each worker, by worker index
+    queue_depths: Mutex<Vec<Arc<AtomicUsize>>>,
+
+    /// workers that died and were started again, counted by the pool
+    worker_restarts: Mutex<Arc<AtomicUsize>>
+}
+
+/// the counters at one point in time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub connections: usize,
+
+    /// topics with at least one subscriber
+    pub topics: usize,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub messages_delivered: u64,
+    pub bytes_delivered: u64
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            topics: self.watched_topics().map_or(0, |index| index.len()),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_delivered: self.messages_delivered.load(Ordering::Relaxed),
+            bytes_delivered: self.bytes_delivered.load(Ordering::Relaxed)
+        }
+    }
+
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// a message of {bytes} (preamble included) was read from a client
+    pub fn received(&self, bytes: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// a notification of {bytes} was written to a subscriber
+    pub fn delivered(&self, bytes: usize) {
+        self.messages_delivered.fetch_add(1, Ordering::Relaxed);
+        self.bytes_delivered.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.writes_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn partial(&self) {
+        self.writes_partial.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unrouted(&self) {
+        self.notifications_unrouted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a client was turned away or had a message dropped because of {limit}
+    pub fn limited(&self, limit: Limit) {
+        self.limited[limit as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// reports the depth of these queues, see threadpool::Mailbox
+    pub fn watch_queues(&self, depths: Vec<Arc<AtomicUsize>>) {
+        *self.queue_depths.lock().unwrap_or_else(|e| e.into_inner()) = depths;
+    }
+
+    /// reports the pool's restart count, see threadpool::StatePool::supervise
+    pub fn watch_restarts(&self, restarts: Arc<AtomicUsize>) {
+        *self.worker_restarts.lock().unwrap_or_else(|e| e.into_inner()) = restarts;
+    }
+
+    /// reports the topics and subscribers in {index}
+    pub fn watch_topics(&self, index: Arc<TopicIndex>) {
+        *self.topics.lock().unwrap_or_else(|e| e.into_inner()) = Some(index);
+    }
+
+    fn watched_topics(&self) -> Option<Arc<TopicIndex>> {
+        self.topics.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// everything in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = write!(out, "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n", name, help, value.load(Ordering::Relaxed));
+        };
+        let _ = write!(out, "# HELP rqueue_connections Open client connections.\n# TYPE rqueue_connections gauge\n\
+                             rqueue_connections {}\n", self.connections.load(Ordering::Relaxed));
+        counter(&mut out, "rqueue_connections_accepted_total", "Client connections accepted.", &self.connections_accepted);
+        counter(&mut out, "rqueue_messages_received_total", "Messages read from clients.", &self.messages_received);
+        counter(&mut out, "rqueue_bytes_received_total", "Bytes read from clients, preambles included.", &self.bytes_received);
+        counter(&mut out, "rqueue_messages_delivered_total", "Notifications written to subscribers.", &self.messages_delivered);
+        counter(&mut out, "rqueue_bytes_delivered_total", "Bytes of notifications written to subscribers.", &self.bytes_delivered);
+        counter(&mut out, "rqueue_writes_dropped_total", "Notifications that were not written to a subscriber.", &self.writes_dropped);
+        counter(&mut out, "rqueue_writes_partial_total", "Notifications that failed part way through.", &self.writes_partial);
+        counter(&mut out, "rqueue_notifications_unrouted_total", "Client notifications nobody was subscribed to.",
+                &self.notifications_unrouted);
+
+        out.push_str("# HELP rqueue_limited_total Connections refused and messages dropped by limit.\n\
+                      # TYPE rqueue_limited_total counter\n");
+        for limit in [Limit::Connections, Limit::Subscriptions, Limit::Messages, Limit::Bytes].iter() {
+            let count = self.limited[*limit as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "rqueue_limited_total{{limit=\"{}\"}} {}", limit.name(), count);
+        }
+
+        // the index is read a shard at a time, so the event loop is only ever held up on one shard
+        let index = self.watched_topics();
+        let _ = write!(out, "# HELP rqueue_topics Topics with at least one subscriber.\n# TYPE rqueue_topics gauge\n\
+                             rqueue_topics {}\n", index.as_ref().map_or(0, |index| index.len()));
+        let _ = write!(out, "# HELP rqueue_subscriptions Subscribers of the {} busiest topics.\n\
+                             # TYPE rqueue_subscriptions gauge\n", MAX_TOPIC_SERIES);
+        for (topic, count) in index.map_or_else(Vec::new, |index| index.busiest(MAX_TOPIC_SERIES)) {
+            let _ = writeln!(out, "rqueue_subscriptions{{topic=\"{}\"}} {}", escape(&topic), count);
+        }
+
+        out.push_str("# HELP rqueue_worker_queue_depth Messages waiting for each worker.\n\
+                      # TYPE rqueue_worker_queue_depth gauge\n");
+        for (i, depth) in self.queue_depths.lock().unwrap_or_else(|e| e.into_inner()).iter().enumerate() {
+            let _ = writeln!(out, "rqueue_worker_queue_depth{{worker=\"{}\"}} {}", i, depth.load(Ordering::Relaxed));
+        }
+        let restarts = self.worker_restarts.lock().unwrap_or_else(|e| e.into_inner()).load(Ordering::Relaxed);
+        let _ = write!(out, "# HELP rqueue_worker_restarts_total Workers that died and were restarted.\n\
+                             # TYPE rqueue_worker_restarts_total counter\nrqueue_worker_restarts_total {}\n", restarts);
+        out
+    }
+}
+
+/// escapes a topic for use as a label value
+fn escape(topic: &[u8]) -> String {
+    let mut escaped = String::new();
+    for c in String::from_utf8_lossy(topic).chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// serves GET /metrics over plain HTTP on a thread of its own. scrapes are answered one at a time
+pub struct Exporter {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Exporter {
+    pub fn start(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<Exporter> {
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let thread = thread::Builder::new().name("rqueue-metrics".to_owned()).spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    if let Err(e) = serve(stream, &metrics) {
+                        debug!(error:% = e; "metrics request failed");
+                    }
+                }
+            }
+        })?;
+        Ok(Exporter {
+            addr,
+            stopped,
+            thread: Some(thread)
+        })
+    }
+
+    /// the address the exporter is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// stops accepting scrapes and waits for the exporter thread
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes the thread up from accept
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST)
+            });
+        }
+        if TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// answers one request
+fn serve(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            return respond(&mut stream, "400 Bad Request", "request too large\n");
+        }
+        match stream.read(&mut buf)? {
+            0 => return Ok(()),
+            n => request.extend_from_slice(&buf[..n])
+        }
+    }
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => respond(&mut stream, "200 OK", &metrics.render()),
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", "try /metrics\n"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "only GET is supported\n")
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}", status, body.len(), body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.connected();
+        metrics.received(10);
+        metrics.delivered(10);
+        metrics.delivered(12);
+        metrics.watch_queues(vec![Arc::new(AtomicUsize::new(3)), Arc::new(AtomicUsize::new(0))]);
+        metrics.watch_restarts(Arc::new(AtomicUsize::new(1)));
+        metrics.unrouted();
+
+        let text = metrics.render();
+        for line in &["rqueue_connections 1", "rqueue_messages_delivered_total 2", "rqueue_bytes_delivered_total 22",
+                      "rqueue_topics 0", "rqueue_worker_queue_depth{worker=\"0\"} 3",
+                      "rqueue_worker_restarts_total 1", "rqueue_notifications_unrouted_total 1",
+                      "# TYPE rqueue_bytes_received_total counter"] {
+            assert!(text.lines().any(|l| l == *line), "{:?} missing from\n{}", line, text);
+        }
+        assert!(!text.contains("rqueue_subscriptions{"));
+    }
+}
@@ -1,9 +1,7 @@
 use mio::TryRead;
-use mio::tcp::TcpStream;
-use std::os::unix::io::{RawFd, AsRawFd};
-use std::net::SocketAddr;
-use std::mem;
-use std::io::Read;
+use std::sync::Arc;
+use std::{io, mem};
+use transport::Peer;
 
 /// fixed stack space for each message
 pub const MAX_STATIC_SZ   : usize = 2048;
@@ -12,20 +10,68 @@ pub const MAX_STATIC_SZ   : usize = 2048;
 pub const PREAMBLE_SZ     : usize = 3;
 pub const PREAMBLE_LEN_SZ : usize = 2;
 
+/// the largest payload that fits in a message
+pub const MAX_PAYLOAD_SZ  : usize = MAX_STATIC_SZ - PREAMBLE_SZ;
+
+/// the longest topic, a NOTIFICATION gives its length in a single byte
+pub const MAX_TOPIC_SZ    : usize = u8::MAX as usize;
+
 // an enumeration on possible message types
-pub const SUBSCRIBE       : u8 = 1; // subscribes a client to a topic, broadcasts to other workers
-pub const REMOVE          : u8 = 2; // removes client intent on topic, broadcasts to other workers
-pub const SUBSCRIBE_ONCE  : u8 = 3; // same as SUBSCRIBE, but no broadcast
-pub const REMOVE_ONCE     : u8 = 4; // same as REMOVE, but no broadcast
-pub const DEREGISTER      : u8 = 5; // purges all subscriptions for a client, and broadcasts
-pub const DEREGISTER_ONCE : u8 = 6; // same as REGISTER, but no broadcast
+pub const SUBSCRIBE       : u8 = 1; // subscribes a client to a topic, handled by the event loop
+pub const REMOVE          : u8 = 2; // removes client intent on topic, handled by the event loop
+                                    // 3 and 4 were used between workers, and are unassigned
+pub const DEREGISTER      : u8 = 5; // purges all subscriptions for a client
+                                    // 6 was used between workers too
 pub const NOTIFICATION    : u8 = 7; // message pertaining to topic sent from a client to the server,
                                     // forwarded directly to interested clients 
+pub const SHUTDOWN        : u8 = 8; // sent from the server to clients when it is going away
+pub const AUTH            : u8 = 9; // credentials sent by a client before anything else, handled by
+                                    // the event loop
+pub const ERROR           : u8 = 10; // sent from the server to a client, usually right before it is
+                                     // disconnected
+pub const AUTH_OK         : u8 = 11; // sent from the server when an AUTH was accepted
+pub const ADMIN           : u8 = 12; // an introspection or control request from an admin, see admin
+pub const ADMIN_REPLY     : u8 = 13; // the answer to an ADMIN, possibly spread over several frames
+pub const PUBLISH         : u8 = 14; // a NOTIFICATION preceded by a 4 byte sequence number, which the
+                                     // server answers with a PUBACK or PUBNACK. subscribers get the
+                                     // NOTIFICATION
+pub const PUBACK          : u8 = 15; // the PUBLISH with this sequence number was routed
+pub const PUBNACK         : u8 = 16; // the PUBLISH was dropped, followed by an error code and reason
+pub const HEADERS         : u8 = 17; // a NOTIFICATION with a block of headers between the topic and
+                                     // the content. only clients that asked for them with FEATURES
+                                     // get it as such, the rest get the NOTIFICATION
+pub const FEATURES        : u8 = 18; // the optional behaviour a client understands, a FEATURE_* bit set
+pub const PUBLISH_HEADERS : u8 = 19; // a HEADERS preceded by a 4 byte sequence number, answered like a
+                                     // PUBLISH. subscribers get the HEADERS, or its NOTIFICATION
+
+// FEATURES bits
+pub const FEATURE_HEADERS : u8 = 1; // HEADERS are delivered with their headers
+
+// header keys with an agreed meaning, any other key can be used too
+pub const HEADER_CONTENT_TYPE : &[u8] = b"content-type"; // e.g. application/json
+pub const HEADER_TRACE_ID     : &[u8] = b"trace-id";
+pub const HEADER_MESSAGE_ID   : &[u8] = b"message-id";
+pub const HEADER_TIMESTAMP    : &[u8] = b"timestamp";    // milliseconds since the unix epoch, 8 bytes
+                                                         // big-endian
+
+// error codes, the first byte of an ERROR payload. the rest is a utf-8 reason
+pub const ERR_AUTH_REQUIRED : u8 = 1; // the listener only accepts AUTH until one succeeds
+pub const ERR_AUTH_FAILED   : u8 = 2; // the credentials were not accepted
+pub const ERR_PERMISSION_DENIED : u8 = 3; // the ACL does not allow the SUBSCRIBE or NOTIFICATION,
+                                          // which is dropped. the client stays connected
+pub const ERR_NOT_FOUND     : u8 = 4; // an ADMIN named a client that is not connected
+pub const ERR_BAD_REQUEST   : u8 = 5; // an ADMIN, PUBLISH or HEADERS that could not be understood, or
+                                      // a SUBSCRIBE to a topic longer than MAX_TOPIC_SZ
+pub const ERR_TOO_MANY_CONNECTIONS   : u8 = 6; // the server or listener is full, sent right before closing
+pub const ERR_TOO_MANY_SUBSCRIPTIONS : u8 = 7; // the SUBSCRIBE is dropped, the client stays connected
+pub const ERR_RATE_LIMITED           : u8 = 8; // messages are being dropped until the client slows down
+pub const ERR_INTERNAL               : u8 = 9; // the server failed to route a PUBLISH
 
 
 /// RawMessage is raw in so far that we have the message in it's entirety
 /// we know the general type but we don't necessarily know what the contents in
 /// the payload is.
+#[derive(Clone)]
 pub struct RawMessage {
     pub m_type: u8,
 
@@ -36,57 +82,120 @@ pub struct RawMessage {
     /// only the first {length} bytes truly represent the message
     /// the rest are considered garbage and should not be used
     pub bytes: [u8; MAX_STATIC_SZ],
-    pub raw_fd: RawFd,
-    pub socket_addr: SocketAddr
+
+    /// the connection the message arrived on, if any
+    pub peer: Option<Arc<Peer>>,
+
+    /// the sequence number of the PUBLISH this came in as, which is owed a PUBACK or PUBNACK
+    pub seq: Option<u32>
 }
 
-/// Gets a message from the socket
-pub fn get_message (socket: &mut TcpStream) -> Option<RawMessage> {
-    let mut message_raw: [u8; MAX_STATIC_SZ] = unsafe { mem::uninitialized() };
+impl RawMessage {
+    /// a message built from a complete frame, e.g. one made by one of the *_message functions
+    pub fn from_frame (frame: &[u8], peer: Option<Arc<Peer>>) -> RawMessage {
+        let mut bytes = [0; MAX_STATIC_SZ];
+        bytes[..frame.len()].copy_from_slice(frame);
+        RawMessage {
+            m_type: frame[PREAMBLE_LEN_SZ],
+            length: frame.len(),
+            bytes,
+            peer,
+            seq: None
+        }
+    }
+
+    /// the NOTIFICATION a PUBLISH carries, or the HEADERS a PUBLISH_HEADERS does. None if it is
+    /// too short to carry one
+    pub fn published (&self) -> Option<RawMessage> {
+        let (seq, notification) = parse_publish(&self.bytes[PREAMBLE_SZ..self.length])?;
+        let mut frame = (notification.len() as u16).to_be_bytes().to_vec();
+        frame.push(if self.m_type == PUBLISH_HEADERS { HEADERS } else { NOTIFICATION });
+        frame.extend_from_slice(notification);
+        let mut message = RawMessage::from_frame(&frame, self.peer.clone());
+        message.seq = Some(seq);
+        Some(message)
+    }
+}
 
-    let mut preamble_read = 0;
-    let payl_size;
-    let m_type;
+/// the part of a frame read off a socket so far. sockets are non blocking, so a frame can arrive
+/// in pieces and what is in has to be kept until the rest shows up
+pub struct PartialFrame {
+    bytes: [u8; MAX_STATIC_SZ],
 
+    /// how many of {bytes} have been read
+    read: usize
+}
+
+impl PartialFrame {
+    pub fn new() -> PartialFrame {
+        PartialFrame { bytes: [0; MAX_STATIC_SZ], read: 0 }
+    }
+}
+
+impl Default for PartialFrame {
+    fn default() -> PartialFrame {
+        PartialFrame::new()
+    }
+}
+
+/// Gets a message from the peer's socket, picking up where {partial} left off. Ok(None) means that
+/// there is nothing more to read for now, whatever part of a frame came in is kept in {partial}. An
+/// error means the connection is unusable: either it was closed (possibly partway through a
+/// message) or the message announced a payload larger than {max_payload}
+pub fn get_message (peer: &Arc<Peer>, partial: &mut PartialFrame, max_payload: usize) -> io::Result<Option<RawMessage>> {
+    let mut socket = peer.stream();
     loop {
-        match socket.try_read(&mut message_raw[preamble_read..PREAMBLE_SZ]) {
-            Ok(Some(0)) if preamble_read == 0 => return None,
-            Ok(Some(num_read)) => {
-                preamble_read += num_read;
-                if preamble_read == PREAMBLE_SZ {
-                    payl_size = u8_2_to_usize(&message_raw[..PREAMBLE_LEN_SZ]);
-                    m_type = message_raw[PREAMBLE_LEN_SZ];
-                    break;
+        // the preamble first, then as much payload as it announces
+        let wanted = match partial.read {
+            read if read < PREAMBLE_SZ => PREAMBLE_SZ,
+            _ => {
+                let payl_size = u8_2_to_usize(&partial.bytes[..PREAMBLE_LEN_SZ]);
+                if payl_size > max_payload.min(MAX_PAYLOAD_SZ) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("payload of {} bytes exceeds the limit of {}", payl_size, max_payload)));
                 }
+                PREAMBLE_SZ + payl_size
             }
-            _ => return None
         };
-    }
-    let mut curr_index = 0;
-    //let mut retries = 0;
-    {
-        let mut payload = &mut message_raw[PREAMBLE_SZ..(payl_size + PREAMBLE_SZ)];
-        loop { //this loop might be bad for the event loop. might be able to abstract into a
-               //coroutine powered by mio
-            match socket.try_read(&mut payload[curr_index..]) {
-                Ok(Some(read)) => {
-                    curr_index += read;
-                    if curr_index == payl_size {
-                        break;
-                    } else { /*retries += 1; */}
-                }
-                _ => { /*retries += 1; */}
+        if partial.read == wanted {
+            break;
+        }
+        match socket.try_read(&mut partial.bytes[partial.read..wanted]) {
+            // a clean close between messages, the client still has to be removed
+            Ok(Some(0)) if partial.read == 0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed")),
+            Ok(Some(0)) if partial.read < PREAMBLE_SZ => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed mid-preamble"))
             }
+            Ok(Some(0)) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed mid-payload")),
+            Ok(Some(read)) => partial.read += read,
+            // the rest of the frame is read once it arrives, the event loop has others to serve
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e)
         }
     }
 
-    return Some(RawMessage {
-        m_type: m_type,
-        length: payl_size + PREAMBLE_SZ,
-        bytes: message_raw,
-        raw_fd: socket.as_raw_fd(),
-        socket_addr: socket.peer_addr().unwrap()
-    })
+    let length = mem::replace(&mut partial.read, 0);
+    Ok(Some(RawMessage {
+        m_type: partial.bytes[PREAMBLE_LEN_SZ],
+        length,
+        bytes: partial.bytes,
+        peer: Some(peer.clone()),
+        seq: None
+    }))
+}
+
+/// topics under this prefix are published by the server itself, clients may only subscribe
+pub const SYS_PREFIX: &[u8] = b"$SYS.";
+
+/// whether {topic} is one of the server's own
+pub fn is_reserved(topic: &[u8]) -> bool {
+    topic.starts_with(SYS_PREFIX) || topic == &SYS_PREFIX[..SYS_PREFIX.len() - 1]
+}
+
+/// the topic of a notification payload, None if the payload is too short to hold it
+pub fn notification_topic(payload: &[u8]) -> Option<&[u8]> {
+    let topic_len = *payload.first()? as usize;
+    payload.get(1..topic_len + 1)
 }
 
 //intended to be used as a client library. someday create an api lib
@@ -95,7 +204,7 @@ pub fn notify_message(topic: &[u8], content: &[u8]) -> Vec<u8> {
     let mut vec = Vec::new();
     let topic_len = [topic.len() as u8];
     let sz = (content.len() + topic.len() + topic_len.len()) as u16;
-    let len:[u8; PREAMBLE_LEN_SZ] = unsafe {mem::transmute(sz.to_be())};
+    let len:[u8; PREAMBLE_LEN_SZ] = sz.to_be_bytes();
 
     vec.extend(len.iter()
                .chain([NOTIFICATION].iter())
@@ -106,11 +215,116 @@ pub fn notify_message(topic: &[u8], content: &[u8]) -> Vec<u8> {
     vec
 }
 
+/// creates a byte representation of a notification with headers. keys are at most 255 bytes,
+/// values at most 65535
+pub fn headers_message(topic: &[u8], headers: &[(&[u8], &[u8])], content: &[u8]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(key, value) in headers {
+        block.push(key.len() as u8);
+        block.extend_from_slice(key);
+        block.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        block.extend_from_slice(value);
+    }
+    let sz = (1 + topic.len() + 2 + block.len() + content.len()) as u16;
+
+    let mut vec = Vec::new();
+    vec.extend_from_slice(&sz.to_be_bytes());
+    vec.push(HEADERS);
+    vec.push(topic.len() as u8);
+    vec.extend_from_slice(topic);
+    vec.extend_from_slice(&(block.len() as u16).to_be_bytes());
+    vec.extend_from_slice(&block);
+    vec.extend_from_slice(content);
+    vec
+}
+
+/// splits a headers payload into the topic, the header block and the content
+pub fn split_headers(payload: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let topic = notification_topic(payload)?;
+    let rest = &payload[1 + topic.len()..];
+    let block_len = u8_2_to_usize(rest.get(..2)?);
+    let block = rest.get(2..2 + block_len)?;
+    Some((topic, block, &rest[2 + block_len..]))
+}
+
+/// the keys and values of a header block, None if it is malformed
+pub fn parse_headers(mut block: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    let mut headers = Vec::new();
+    while let Some((&key_len, rest)) = block.split_first() {
+        let key = rest.get(..key_len as usize)?;
+        let rest = &rest[key_len as usize..];
+        let value_len = u8_2_to_usize(rest.get(..2)?);
+        let value = rest.get(2..2 + value_len)?;
+        headers.push((key, value));
+        block = &rest[2 + value_len..];
+    }
+    Some(headers)
+}
+
+/// the notification a headers frame carries, for clients that don't understand headers
+pub fn strip_headers(frame: &[u8]) -> Option<Vec<u8>> {
+    let (topic, _, content) = split_headers(frame.get(PREAMBLE_SZ..)?)?;
+    Some(notify_message(topic, content))
+}
+
+/// creates a byte representation of a features message
+pub fn features_message(features: u8) -> Vec<u8> {
+    vec![0, 1, FEATURES, features]
+}
+
+/// creates a byte representation of a publish message, a notification the server confirms
+pub fn publish_message(seq: u32, topic: &[u8], content: &[u8]) -> Vec<u8> {
+    confirmed(seq, PUBLISH, notify_message(topic, content))
+}
+
+/// creates a byte representation of a publish headers message, a notification with headers the
+/// server confirms
+pub fn publish_headers_message(seq: u32, topic: &[u8], headers: &[(&[u8], &[u8])], content: &[u8]) -> Vec<u8> {
+    confirmed(seq, PUBLISH_HEADERS, headers_message(topic, headers, content))
+}
+
+/// turns {frame} into an {m_type} by putting {seq} in front of its payload
+fn confirmed(seq: u32, m_type: u8, mut vec: Vec<u8>) -> Vec<u8> {
+    let sz = (vec.len() - PREAMBLE_SZ + 4) as u16;
+    vec[..PREAMBLE_LEN_SZ].copy_from_slice(&sz.to_be_bytes());
+    vec[PREAMBLE_LEN_SZ] = m_type;
+    vec.splice(PREAMBLE_SZ..PREAMBLE_SZ, seq.to_be_bytes().iter().cloned());
+    vec
+}
+
+/// splits a publish or publish headers payload into the sequence number and the notification or
+/// headers payload
+pub fn parse_publish(payload: &[u8]) -> Option<(u32, &[u8])> {
+    let seq = payload.get(..4)?;
+    Some((u32::from_be_bytes([seq[0], seq[1], seq[2], seq[3]]), &payload[4..]))
+}
+
+/// creates a byte representation of a puback message
+pub fn puback_message(seq: u32) -> Vec<u8> {
+    let mut vec = vec![0, 4, PUBACK];
+    vec.extend_from_slice(&seq.to_be_bytes());
+    vec
+}
+
+/// creates a byte representation of a pubnack message
+pub fn pubnack_message(seq: u32, code: u8, reason: &str) -> Vec<u8> {
+    let mut vec = Vec::new();
+    let sz = (4 + 1 + reason.len()) as u16;
+    let len:[u8; PREAMBLE_LEN_SZ] = sz.to_be_bytes();
+
+    vec.extend(len.iter()
+               .chain([PUBNACK].iter())
+               .chain(seq.to_be_bytes().iter())
+               .chain([code].iter())
+               .chain(reason.as_bytes().iter()));
+    vec
+}
+
 /// creates a byte representation of a subscribe message
 pub fn subscribe_message(topic: &[u8]) -> Vec<u8> {
     let mut vec = Vec::new();
     let sz = topic.len() as u16;
-    let len:[u8; PREAMBLE_LEN_SZ] = unsafe {mem::transmute(sz.to_be())};
+    let len:[u8; PREAMBLE_LEN_SZ] = sz.to_be_bytes();
 
     vec.extend(len.iter()
                .chain([SUBSCRIBE].iter())
@@ -118,14 +332,77 @@ pub fn subscribe_message(topic: &[u8]) -> Vec<u8> {
     vec
 }
 
+/// creates a byte representation of a remove message
+pub fn remove_message(topic: &[u8]) -> Vec<u8> {
+    let mut vec = Vec::new();
+    let sz = topic.len() as u16;
+    let len:[u8; PREAMBLE_LEN_SZ] = sz.to_be_bytes();
+
+    vec.extend(len.iter()
+               .chain([REMOVE].iter())
+               .chain(topic.iter()));
+    vec
+}
+
+/// creates a byte representation of a deregister message
+pub fn deregister_message() -> Vec<u8> {
+    vec![0, 0, DEREGISTER]
+}
+
+/// creates a byte representation of an auth message. {user} is empty when {secret} is a token
+pub fn auth_message(user: &[u8], secret: &[u8]) -> Vec<u8> {
+    let mut vec = Vec::new();
+    let user_len = [user.len() as u8];
+    let sz = (user_len.len() + user.len() + secret.len()) as u16;
+    let len:[u8; PREAMBLE_LEN_SZ] = sz.to_be_bytes();
+
+    vec.extend(len.iter()
+               .chain([AUTH].iter())
+               .chain(user_len.iter())
+               .chain(user.iter())
+               .chain(secret.iter()));
+    vec
+}
+
+/// splits an auth payload into the user and secret
+pub fn parse_auth(payload: &[u8]) -> Option<(&[u8], &[u8])> {
+    let user_len = *payload.first()? as usize;
+    if payload.len() < user_len + 1 {
+        return None;
+    }
+    Some((&payload[1..user_len + 1], &payload[user_len + 1..]))
+}
+
+/// creates a byte representation of an error message
+pub fn error_message(code: u8, reason: &str) -> Vec<u8> {
+    let mut vec = Vec::new();
+    let sz = (1 + reason.len()) as u16;
+    let len:[u8; PREAMBLE_LEN_SZ] = sz.to_be_bytes();
+
+    vec.extend(len.iter()
+               .chain([ERROR, code].iter())
+               .chain(reason.as_bytes().iter()));
+    vec
+}
+
+/// creates a byte representation of an auth ok message
+pub fn auth_ok_message() -> Vec<u8> {
+    vec![0, 0, AUTH_OK]
+}
+
+/// creates a byte representation of a shutdown message
+pub fn shutdown_message() -> Vec<u8> {
+    vec![0, 0, SHUTDOWN]
+}
+
 pub fn u8_4_to_u32 (bytes: &[u8]) -> usize {
-    (bytes[3] as usize
+    bytes[3] as usize
         | ((bytes[2] as usize) << 8)
         | ((bytes[1] as usize) << 16)
-        | ((bytes[0] as usize) << 24))
+        | ((bytes[0] as usize) << 24)
 }
 
 pub fn u8_2_to_usize (bytes: &[u8]) -> usize {
-    (bytes[1] as usize
-        | ((bytes[0] as usize) << 8))
+    bytes[1] as usize
+        | ((bytes[0] as usize) << 8)
 }
@@ -1,172 +1,140 @@
-use std::sync::mpsc::{Sender};
-use std::net::{TcpStream, SocketAddr};
-use std::os::unix::io::{FromRawFd, RawFd};
-use std::collections::{HashMap, HashSet};
-use std::io::Write;
-use std::ptr;
-use net2::TcpBuilder;
-use slice_map::SliceMap;
-use protocol::{RawMessage, PREAMBLE_SZ};
-use protocol::{NOTIFICATION, SUBSCRIBE, SUBSCRIBE_ONCE, REMOVE, REMOVE_ONCE, DEREGISTER, DEREGISTER_ONCE};
-
-/* for reference
-pub struct RawMessage {
-    pub m_type: u8,
-    pub length: usize,
-    pub bytes: [u8; MAX_STATIC_SZ],
-    pub raw_fd: RawFd
-}
- */
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use topics::TopicIndex;
+use metrics::Metrics;
+use protocol::{RawMessage, PREAMBLE_SZ, NOTIFICATION, HEADERS, FEATURE_HEADERS, notification_topic, strip_headers};
+use threadpool::PoolWorker;
 
-//TODO: keying by SocketAddr alone seems like it could potentially be dangerous. perhaps should add a uid. if the socket is reused by a different subscriber and
-//ends up mapping to a previous fd - it might be able to receive traffic that it was not interested in. e.g. a client disconnects then reconnects and happens
-//to get accepted to the same socket, and receives messages before the server can purge interests. Think about this more.
-// the way we're currently handling disconnects right now, sending deregisters from the EL thread makes this a non-issue though
-/// does something, given work denoted as a RawMessage. Many operations are on a SliceMap, which is
-/// a handrolled specialized datastructure
-pub fn parse(work: &RawMessage, contacts: &[Sender<RawMessage>], state_map: &mut SliceMap<HashMap<SocketAddr, TcpStream>>, interest_map: &mut HashMap<SocketAddr, HashSet<Vec<u8>>>) {
+/// what became of one message, handed back to the event loop
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    /// the connection the message came in on, None for the server's own
+    pub publisher: Option<usize>,
 
-    //the message excluding the preamble
-    let payload = &work.bytes[PREAMBLE_SZ..];
+    /// the sequence number to confirm, if the message came in as a PUBLISH
+    pub seq: Option<u32>,
 
-    match work.m_type {
-        NOTIFICATION => {
-            //topic len is a one byte value (<255)
-            let topic_len = payload[0] as usize;
-            let topic = &payload[1..topic_len+1];
-
-            // forwards the NOTIFICATION to each interested party
-            state_map.apply(topic, |ref mut h_entry| {
-                for (addr, tcp_stream) in h_entry.iter_mut() {
-                    //TcpStreams in Rust are wrappers around raw file descriptors
-                    //this outer check is for runtime protection
-                    match tcp_stream.peer_addr() {
-                        Ok(ref a) if a == addr => {
-                            let mut index = 0;
-                            loop {
-                                //println!("{}", work.length);
-                                match tcp_stream.write(&work.bytes[index..work.length]) {
-                                    Ok(just_written) => {
-                                        index += just_written;
-                                        if index == work.length {
-                                            break;
-                                        } else {
-                                            //this is potentially bad, but does not seem to happen
-                                            //if our buffer is < 4KB. (possibly because of ethernet
-                                            //MTU?)
-                                            //
-                                            //not sure what happens with multithreadedness
-                                            //but while send() syscalls are considered atomic partials are
-                                            //possible in theory -> might have to break our rqueue
-                                            //protocol to allow multi-part
-                                            println!("partial");
-                                        }
-                                    },
-                                    _ => {
-                                        //this is dangerous as it will retry even from a
-                                        //potentially unrecoverable error. perhaps enforce a max
-                                        //timeout or retry limit
-                                        //println!("err writing: {:?}", e);
-                                    }
-                                };
-                            }
-                        },
-                        _ => ()
-                    };
-                }
-            });
-        }
+    /// the subscribers it was fanned out to
+    pub subscribers: usize,
 
-        // subscribes the client sender to one topic
-        SUBSCRIBE |  SUBSCRIBE_ONCE => {
+    /// the subscribers it was written to in full
+    pub delivered: usize,
 
-            let topic = &payload[..work.length - PREAMBLE_SZ];
-            let c = interest_map.entry(work.socket_addr).or_insert(HashSet::new());
-            c.insert(topic.to_owned());
+    /// why it could not be routed at all
+    pub error: Option<&'static str>
+}
 
-            // add the subscribe to our map
-            state_map.modify_or_else(topic, |ref mut map| {
-                    let tcp_stream = to_std_tcpstream_from_raw(work.raw_fd.clone());
-                    if tcp_stream.peer_addr().unwrap() == work.socket_addr {
-                        map.insert(tcp_stream.peer_addr().unwrap(), tcp_stream);
-                    }
-                }, || {
-                    let mut map = HashMap::new();
-                    let tcp_stream = to_std_tcpstream_from_raw(work.raw_fd.clone());
-                    if tcp_stream.peer_addr().unwrap() == work.socket_addr {
-                        map.insert(tcp_stream.peer_addr().unwrap(), tcp_stream);
-                    }
-                    map
-                }
-            );
-
-            if work.m_type == SUBSCRIBE { //broadcast a sub once to the other workers
-                println!("sub topic: {:?}", &topic);
-                for sender in contacts.iter() {
-                    let mut u = unsafe{ ptr::read(work) };
-                    u.m_type = SUBSCRIBE_ONCE;
-                    let _ = sender.send(u);
-                }
-            }
+impl Delivery {
+    /// {message} was not routed at all, because of {error}
+    pub fn failed(message: &RawMessage, error: &'static str) -> Delivery {
+        Delivery {
+            publisher: message.peer.as_ref().map(|p| p.id()),
+            seq: message.seq,
+            error: Some(error),
+            ..Delivery::default()
         }
+    }
+}
 
-        // removes one topic from a clients subscriptions
-        REMOVE | REMOVE_ONCE => {
-            let topic = &payload[..work.length - PREAMBLE_SZ];
-            let remove_topic = state_map.modify(topic, |ref mut map| {
-                map.remove(&work.socket_addr);
-                match map.is_empty() {
-                    true => Some(true),
-                    _ => None
-                }
-            });
-            if remove_topic == Some(true) {
-                state_map.delete(payload);
-            }
-            if work.m_type == REMOVE { //broadcast a remove once to the other workers
-                println!("removing, {:?}", topic);
-                for sender in contacts.iter() {
-                    let mut u = unsafe{ ptr::read(work) };
-                    u.m_type = REMOVE_ONCE;
-                    let _ = sender.send(u);
-                }
-            }
+/// the server's PoolWorker, handing each message it gets to parse
+pub struct QueuePoolWorker {
+
+    /// mapping of topics (a bunch of bytes) to their subscribers, shared with the event loop and
+    /// the other workers
+    topics: Arc<TopicIndex>,
+
+    /// where deliveries are counted
+    metrics: Arc<Metrics>
+}
+
+impl QueuePoolWorker {
+    /// a worker that delivers to the subscribers in {topics} and counts its deliveries in
+    /// {metrics}
+    pub fn with_index(topics: Arc<TopicIndex>, metrics: Arc<Metrics>) -> QueuePoolWorker {
+        QueuePoolWorker {
+            topics,
+            metrics
         }
+    }
+}
 
-        // purges all subscriptions for a client
-        DEREGISTER | DEREGISTER_ONCE => {
-            if let Some(set) = interest_map.remove(&work.socket_addr) {
-                for topic in set.iter() {
-                    let remove_topic = state_map.modify(topic, |ref mut map| {
-                        map.remove(&work.socket_addr);
-                        match map.is_empty() {
-                            true => Some(true),
-                            _ => None
-                        }
-                    });
-                    if remove_topic == Some(true) {
-                        println!("removing topic {:?}", topic);
-                        state_map.delete(topic);
-                    }
+impl PoolWorker<RawMessage, Delivery> for QueuePoolWorker {
+    /// does something with a message. a panic is reported like any other failure rather than
+    /// taking the worker down, so that every message gets its Delivery
+    fn func (&mut self, message: &RawMessage) -> Delivery {
+        let (topics, metrics) = (&self.topics, &self.metrics);
+        panic::catch_unwind(AssertUnwindSafe(|| parse(message, topics, metrics)))
+            .unwrap_or_else(|_| Delivery::failed(message, "panicked"))
+    }
+
+    /// a worker that dies all the same still accounts for the message it had
+    fn lost (message: &RawMessage) -> Option<Delivery> {
+        Some(Delivery::failed(message, "worker died"))
+    }
+}
+
+/// does something, given work denoted as a RawMessage. subscriptions are kept in a TopicIndex
+/// shared with the event loop, which is the only one to change it, so all that is left for the
+/// workers is fanning notifications out
+pub fn parse(work: &RawMessage, topics: &TopicIndex, metrics: &Metrics) -> Delivery {
+    let mut delivery = Delivery {
+        publisher: work.peer.as_ref().map(|p| p.id()),
+        seq: work.seq,
+        ..Delivery::default()
+    };
+
+    //the message excluding the preamble
+    let payload = &work.bytes[PREAMBLE_SZ..work.length];
+
+    match work.m_type {
+        NOTIFICATION | HEADERS => {
+            let topic = match notification_topic(payload) {
+                Some(topic) => topic,
+                None => {
+                    warn!("notification too short for its topic");
+                    delivery.error = Some("malformed notification");
+                    return delivery;
                 }
-            }
+            };
 
-            if work.m_type == DEREGISTER { //broadcast is done from the event loop for now
-                for sender in contacts.iter() {
-                    let mut u = unsafe {ptr::read(work) };
-                    u.m_type = DEREGISTER_ONCE;
-                    let _ = sender.send(u);
+            // forwards the NOTIFICATION to each interested party. the list is ours, the index
+            // is not locked while writing
+            let frame = &work.bytes[..work.length];
+            let subscribers = match topics.subscribers(topic) {
+                Some(subscribers) => subscribers,
+                None => return delivery
+            };
+            delivery.subscribers = subscribers.len();
+            // built on the first subscriber that didn't ask for headers. the event loop has
+            // checked the header block already
+            let mut plain = None;
+            for peer in subscribers.iter() {
+                //a failed write means the peer is going away, the event loop will
+                //deregister it
+                if peer.is_closed() {
+                    metrics.dropped();
+                    continue;
+                }
+                let frame: &[u8] = match work.m_type {
+                    HEADERS if peer.features() & FEATURE_HEADERS == 0 => {
+                        plain.get_or_insert_with(|| strip_headers(frame).unwrap_or_default())
+                    }
+                    _ => frame
+                };
+                match peer.send(frame) {
+                    Ok(()) => {
+                        metrics.delivered(frame.len());
+                        delivery.delivered += 1;
+                    }
+                    Err(ref e) if e.is_partial() => metrics.partial(),
+                    Err(_) => metrics.dropped()
                 }
             }
         }
-        _ => {
-            println!("none");
+        m_type => {
+            warn!(m_type = m_type; "unexpected message type");
+            delivery.error = Some("unexpected message type");
         }
     }
-}
-
-// converts a raw fd (a 32-bit C integer) to std::net::TcpStream
-fn to_std_tcpstream_from_raw(fd: RawFd) -> TcpStream {
-    let builder = unsafe { TcpBuilder::from_raw_fd(fd.clone()) };
-    builder.to_tcp_stream().unwrap()
+    delivery
 }
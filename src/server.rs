@@ -0,0 +1,1370 @@
+use std::{fmt, io, mem, thread};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::{self, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use mio;
+use mio::tcp::TcpListener;
+use mio::unix::EventedFd;
+use mio::{Token, EventSet, EventLoop, PollOpt, Handler};
+use net2::TcpBuilder;
+use protocol::{self, RawMessage, PartialFrame, MAX_PAYLOAD_SZ, MAX_TOPIC_SZ, PREAMBLE_SZ, AUTH, SUBSCRIBE, REMOVE, DEREGISTER, NOTIFICATION};
+use protocol::{HEADERS, FEATURES, ADMIN, PUBLISH, PUBLISH_HEADERS, ERR_INTERNAL, ERR_AUTH_REQUIRED, ERR_AUTH_FAILED, ERR_PERMISSION_DENIED, ERR_NOT_FOUND, ERR_BAD_REQUEST};
+use protocol::{ERR_TOO_MANY_CONNECTIONS, ERR_TOO_MANY_SUBSCRIPTIONS, ERR_RATE_LIMITED};
+use protocol::{get_message, shutdown_message, error_message, auth_ok_message, notify_message};
+use protocol::{puback_message, pubnack_message};
+use auth::{Authenticator, Credentials, User};
+use acl::{Acl, Permissions};
+use admin::{self, Request, ClientInfo, TopicInfo};
+use threadpool::{StatePool, DEFAULT_CAPACITY};
+use rpc::{QueuePoolWorker, Delivery};
+use metrics::{Metrics, Exporter, Snapshot};
+use limits::{Limit, RateLimit, RateLimiter};
+use slice_map::Hashing;
+use topics::{TopicIndex, DEFAULT_SHARDS};
+use transport::{ListenAddr, ListenSocket, Peer, Stream};
+use tls::{TlsConfig, TlsStream};
+use rustls;
+
+pub const DEFAULT_PORT: u16 = 6567;
+pub const DEFAULT_WORKERS: usize = 8;
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_SYS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// backlog of pending connections for each listener
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// bytes of replies queued for a client past which nothing more is read from it, until it reads
+/// some of them
+const MAX_QUEUED: usize = 64 * 1024;
+
+/// messages that can be sent to a running server from other threads
+pub enum Command {
+    /// stops accepting connections, drains the workers and stops the event loop
+    Shutdown,
+
+    /// replaces the ACL. subscriptions it no longer allows are removed
+    SetAcl(Arc<Acl>),
+
+    /// sent by the workers as they finish work, make room in their queues or die, so that their
+    /// results are read, dead workers are restarted and paused publishers are read from again
+    Progress,
+
+    /// sent by the Authenticator once it has checked the AUTH of a client, with the user the
+    /// credentials belong to, if they were good
+    Authenticated(Token, Option<Arc<User>>)
+}
+
+/// timers the event loop sets on itself
+enum Timer {
+    /// publishes the $SYS topics
+    SysStats,
+
+    /// stops the event loop, dropping the clients that have not read what they are owed
+    Stop
+}
+
+/// what the server did over its lifetime, returned once it has shut down
+#[derive(Debug, Default, Clone)]
+pub struct ShutdownSummary {
+    /// connections accepted since the server started
+    pub connections_accepted: u64,
+
+    /// connections still open when the server shut down
+    pub connections_open: usize,
+
+    /// messages read from clients and handed to the workers
+    pub messages_received: u64,
+
+    /// workers that finished their queued work within the drain timeout
+    pub workers_drained: usize,
+
+    /// workers that were abandoned with work still queued
+    pub workers_abandoned: usize,
+
+    /// how long draining took
+    pub drain_time: Duration
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "accepted {} connections ({} open at shutdown), received {} messages, \
+                   drained {} workers ({} abandoned) in {:?}",
+               self.connections_accepted, self.connections_open, self.messages_received,
+               self.workers_drained, self.workers_abandoned, self.drain_time)
+    }
+}
+
+/// settings for one listening socket. limits that are left unset fall back to the server wide
+/// ones
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub addr: ListenAddr,
+
+    /// connections accepted on this listener at once
+    pub max_connections: Option<usize>,
+
+    /// the largest payload a client of this listener may send
+    pub max_payload: Option<usize>,
+
+    /// when set, clients have to connect over TLS
+    pub tls: Option<TlsConfig>,
+
+    /// whether clients have to AUTH before anything else
+    pub require_auth: Option<bool>
+}
+
+impl ListenerConfig {
+    pub fn new<A: Into<ListenAddr>>(addr: A) -> ListenerConfig {
+        ListenerConfig {
+            addr: addr.into(),
+            max_connections: None,
+            max_payload: None,
+            tls: None,
+            require_auth: None
+        }
+    }
+
+    pub fn max_connections(mut self, max: usize) -> ListenerConfig {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn max_payload(mut self, max: usize) -> ListenerConfig {
+        self.max_payload = Some(max);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> ListenerConfig {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn require_auth(mut self, required: bool) -> ListenerConfig {
+        self.require_auth = Some(required);
+        self
+    }
+
+    /// binds a non blocking listener. IPv6 listeners only accept IPv6, so that they can share a
+    /// port with an IPv4 listener
+    fn bind(&self) -> io::Result<ListenSocket> {
+        let addr = match self.addr {
+            ListenAddr::Tcp(addr) => addr,
+            ListenAddr::Unix(ref path) => return ListenSocket::bind_unix(path.clone())
+        };
+        let builder = match addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let b = TcpBuilder::new_v6()?;
+                b.only_v6(true)?;
+                b
+            }
+        };
+        builder.reuse_address(true)?;
+        builder.bind(addr)?;
+        let listener: net::TcpListener = builder.listen(LISTEN_BACKLOG)?;
+        let addr = listener.local_addr()?;
+        TcpListener::from_listener(listener, &addr).map(ListenSocket::Tcp)
+    }
+}
+
+/// settings shared by the builder, the server and its event loop
+#[derive(Clone)]
+struct Settings {
+    workers: usize,
+    queue_depth: usize,
+    max_connections: usize,
+    max_payload: usize,
+    drain_timeout: Duration,
+    notify_shutdown: bool,
+    credentials: Option<Arc<Credentials>>,
+    require_auth: Option<bool>,
+    acl: Option<Arc<Acl>>,
+    metrics: Option<SocketAddr>,
+    sys_interval: Duration,
+    max_subscriptions: usize,
+    rate_limit: RateLimit,
+    topic_hashing: Hashing
+}
+
+impl Settings {
+    /// authentication is required by default once there are credentials to check against
+    fn require_auth(&self, listener: &ListenerConfig) -> bool {
+        listener.require_auth.or(self.require_auth).unwrap_or(self.credentials.is_some())
+    }
+}
+
+/// configures and binds a Server
+pub struct ServerBuilder {
+    listeners: Vec<ListenerConfig>,
+    settings: Settings
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder {
+            listeners: Vec::new(),
+            settings: Settings {
+                workers: DEFAULT_WORKERS,
+                queue_depth: DEFAULT_CAPACITY,
+                max_connections: usize::MAX,
+                max_payload: MAX_PAYLOAD_SZ,
+                drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+                notify_shutdown: false,
+                credentials: None,
+                require_auth: None,
+                acl: None,
+                metrics: None,
+                sys_interval: DEFAULT_SYS_INTERVAL,
+                max_subscriptions: usize::MAX,
+                rate_limit: RateLimit::default(),
+                topic_hashing: Hashing::keyed()
+            }
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// adds a listener on {addr} with the server wide settings. use port 0 to have the OS pick
+    /// one. if no listener is added the server listens on 0.0.0.0:DEFAULT_PORT
+    pub fn bind<A: Into<ListenAddr>>(self, addr: A) -> ServerBuilder {
+        self.listen(ListenerConfig::new(addr))
+    }
+
+    /// adds a listener with its own settings
+    pub fn listen(mut self, listener: ListenerConfig) -> ServerBuilder {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// the number of auxiliary worker threads messages are fanned out on
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.settings.workers = workers;
+        self
+    }
+
+    /// how many notifications may wait for each worker. once every queue is full, publishers
+    /// are not read from until there is room again, which pushes back on them through TCP
+    pub fn queue_depth(mut self, depth: usize) -> ServerBuilder {
+        self.settings.queue_depth = depth;
+        self
+    }
+
+    /// the maximum number of concurrent client connections over all listeners, further accepts
+    /// are closed immediately
+    pub fn max_connections(mut self, max: usize) -> ServerBuilder {
+        self.settings.max_connections = max;
+        self
+    }
+
+    /// the largest payload a client may send, in bytes. clients that send larger messages are
+    /// disconnected. capped at MAX_PAYLOAD_SZ
+    pub fn max_payload(mut self, max: usize) -> ServerBuilder {
+        self.settings.max_payload = max;
+        self
+    }
+
+    /// the most topics one client may be subscribed to at once. further SUBSCRIBEs are dropped
+    /// and answered with an ERROR
+    pub fn max_subscriptions(mut self, max: usize) -> ServerBuilder {
+        self.settings.max_subscriptions = max;
+        self
+    }
+
+    /// how fast each client may send. messages over the limit are dropped, and the client is
+    /// told with an ERROR when it starts being limited. a rate of 0 drops everything
+    pub fn rate_limit(mut self, limit: RateLimit) -> ServerBuilder {
+        self.settings.rate_limit = limit;
+        self
+    }
+
+    /// how the workers hash topics. keyed by default, since topics are picked by clients and
+    /// colliding ones would slow down every lookup. Hashing::Fast is quicker when they are trusted
+    pub fn topic_hashing(mut self, hashing: Hashing) -> ServerBuilder {
+        self.settings.topic_hashing = hashing;
+        self
+    }
+
+    /// how long to wait on shutdown for the workers to deliver messages already handed to them,
+    /// and then for clients to read the replies they are owed
+    pub fn drain_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.settings.drain_timeout = timeout;
+        self
+    }
+
+    /// whether to send every connected client a SHUTDOWN frame when the server goes away
+    pub fn notify_shutdown(mut self, notify: bool) -> ServerBuilder {
+        self.settings.notify_shutdown = notify;
+        self
+    }
+
+    /// the users clients may AUTH as. once set, every listener requires authentication unless
+    /// told otherwise
+    pub fn credentials(mut self, credentials: Credentials) -> ServerBuilder {
+        self.settings.credentials = Some(Arc::new(credentials));
+        self
+    }
+
+    /// whether clients have to AUTH before anything else, for listeners that don't say
+    pub fn require_auth(mut self, required: bool) -> ServerBuilder {
+        self.settings.require_auth = Some(required);
+        self
+    }
+
+    /// limits what each user may publish and subscribe to. without one everything is allowed
+    pub fn acl(mut self, acl: Acl) -> ServerBuilder {
+        self.settings.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// serves Prometheus metrics over HTTP on {addr}, at /metrics
+    pub fn metrics(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.settings.metrics = Some(addr);
+        self
+    }
+
+    /// how often server statistics are published on the $SYS topics. zero turns them off
+    pub fn sys_interval(mut self, interval: Duration) -> ServerBuilder {
+        self.settings.sys_interval = interval;
+        self
+    }
+
+    /// binds the listeners. nothing is accepted until the server is run
+    pub fn build(mut self) -> io::Result<Server> {
+        if self.settings.workers == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "at least one worker is required"));
+        }
+        if self.settings.queue_depth == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "worker queues must hold at least one message"));
+        }
+        if self.listeners.is_empty() {
+            self.listeners.push(ListenerConfig::new(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))));
+        }
+        let payloads = self.listeners.iter().filter_map(|l| l.max_payload);
+        if payloads.chain(Some(self.settings.max_payload)).any(|p| p > MAX_PAYLOAD_SZ) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("payloads are capped at {} bytes", MAX_PAYLOAD_SZ)));
+        }
+
+        if self.settings.credentials.is_none() && self.listeners.iter().any(|l| self.settings.require_auth(l)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "authentication is required but there are no credentials"));
+        }
+
+        let exporter = match self.settings.metrics {
+            Some(addr) => Some(net::TcpListener::bind(addr).map_err(|e| {
+                io::Error::new(e.kind(), format!("could not serve metrics on {}: {}", addr, e))
+            })?),
+            None => None
+        };
+
+        let mut listeners = Vec::new();
+        for config in self.listeners {
+            // certificates are checked before binding so a bad one leaves no socket file behind
+            let tls = match config.tls {
+                Some(ref tls) => Some(tls.server_config().map_err(|e| {
+                    io::Error::new(e.kind(), format!("could not set up tls for {}: {}", config.addr, e))
+                })?),
+                None => None
+            };
+            let socket = config.bind().map_err(|e| {
+                io::Error::new(e.kind(), format!("could not listen on {}: {}", config.addr, e))
+            })?;
+            listeners.push(Listener {
+                socket: Some(socket),
+                config,
+                tls,
+                connections: 0
+            });
+        }
+        Ok(Server {
+            listeners,
+            settings: self.settings,
+            metrics: Arc::new(Metrics::new()),
+            exporter
+        })
+    }
+}
+
+/// an rqueue server that can be embedded in another process
+pub struct Server {
+    listeners: Vec<Listener>,
+    settings: Settings,
+    metrics: Arc<Metrics>,
+
+    /// bound at build time, served once the server runs
+    exporter: Option<net::TcpListener>
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// the address the first tcp listener is actually bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        for listener in self.listeners.iter() {
+            if let Some(addr) = listener.local_addr()?.tcp() {
+                return Ok(addr);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, "no tcp listener"))
+    }
+
+    /// the addresses every listener is actually bound to, in the order they were added
+    pub fn local_addrs(&self) -> io::Result<Vec<ListenAddr>> {
+        self.listeners.iter().map(|l| l.local_addr()).collect()
+    }
+
+    /// the address metrics are served on, if they are
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.exporter.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// the server's counters, updated as it runs
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// runs the server on the current thread, blocks until it is shut down
+    pub fn run(self) -> io::Result<ShutdownSummary> {
+        let mut event_loop = EventLoop::new()?;
+        self.run_on(&mut event_loop)
+    }
+
+    /// runs the server on a background thread
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let addrs = self.local_addrs()?;
+        let metrics_addr = self.metrics_addr();
+        let metrics = self.metrics();
+        let (tx, rx) = channel();
+
+        let thread = thread::Builder::new().name("rqueue-event-loop".to_owned()).spawn(move || {
+            let mut event_loop = match EventLoop::new() {
+                Ok(e) => e,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return Ok(ShutdownSummary::default())
+                }
+            };
+            let _ = tx.send(Ok(event_loop.channel()));
+            self.run_on(&mut event_loop)
+        })?;
+
+        match rx.recv() {
+            Ok(Ok(sender)) => Ok(ServerHandle {
+                addrs,
+                metrics_addr,
+                metrics,
+                sender,
+                thread: Some(thread)
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::other("event loop thread exited before starting"))
+        }
+    }
+
+    fn run_on(self, event_loop: &mut EventLoop<RQueueServer>) -> io::Result<ShutdownSummary> {
+        // listeners take the first tokens, clients are numbered after them
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if let Some(ref socket) = listener.socket {
+                event_loop.register(&EventedFd(&socket.as_raw_fd()), Token(i), EventSet::readable(), PollOpt::edge())?;
+            }
+        }
+
+        let metrics = self.metrics;
+        let topics = Arc::new(TopicIndex::new(DEFAULT_SHARDS, self.settings.topic_hashing.clone()));
+        // decoupled worker pool with configurable # of
+        // threads. workers keep nothing of their own, so one that dies is simply made again
+        let worker_topics = topics.clone();
+        let worker_metrics = metrics.clone();
+        let worker_pool = StatePool::with_capacity(self.settings.workers, self.settings.queue_depth, move |_| {
+            QueuePoolWorker::with_index(worker_topics.clone(), worker_metrics.clone())
+        });
+        // one wake up in flight is enough, the event loop reads every result and retries every
+        // paused publisher
+        let wake_pending = Arc::new(AtomicBool::new(false));
+        let (wake, pending) = (event_loop.channel(), wake_pending.clone());
+        let wake_up = move || {
+            if !pending.swap(true, Ordering::AcqRel) {
+                let _ = wake.send(Command::Progress);
+            }
+        };
+        worker_pool.when_freed(wake_up.clone());
+        worker_pool.when_done(wake_up);
+        metrics.watch_queues(worker_pool.queue_depths());
+        metrics.watch_restarts(worker_pool.restarts());
+        metrics.watch_topics(topics.clone());
+        let exporter = match self.exporter {
+            Some(listener) => Some(Exporter::start(listener, metrics.clone())?),
+            None => None
+        };
+
+        // bcrypt is slow on purpose, so credentials are checked on a thread of their own
+        let authenticator = match self.settings.credentials {
+            Some(ref credentials) => {
+                let done = event_loop.channel();
+                Some(Authenticator::start(credentials.clone(), move |id, user| {
+                    let _ = done.send(Command::Authenticated(Token(id), user));
+                })?)
+            }
+            None => None
+        };
+
+        let sys_timer = match self.settings.sys_interval {
+            interval if interval.is_zero() => None,
+            interval => Some(event_loop.timeout_ms(Timer::SysStats, interval.as_millis() as u64)
+                .map_err(|e| io::Error::other(format!("could not set the $SYS timer: {:?}", e)))?)
+        };
+
+        let mut server = RQueueServer {
+            token_counter: self.listeners.len() - 1,
+            listeners: self.listeners,
+            clients: HashMap::new(),
+            summary: ShutdownSummary::default(),
+            worker_pool,
+            topics,
+            acl: self.settings.acl.clone().unwrap_or_else(|| Arc::new(Acl::allow_all())),
+            authenticator,
+            settings: self.settings,
+            started: Instant::now(),
+            last_stats: (Instant::now(), metrics.snapshot()),
+            sys_timer,
+            metrics,
+            admin_requests: Vec::new(),
+            wake_pending,
+            paused: Vec::new(),
+            closing: false
+        };
+        let result = event_loop.run(&mut server);
+        if let Some(exporter) = exporter {
+            exporter.stop();
+        }
+        result?;
+        Ok(server.summary)
+    }
+}
+
+/// a handle to a server running on a background thread
+pub struct ServerHandle {
+    addrs: Vec<ListenAddr>,
+    metrics_addr: Option<SocketAddr>,
+    metrics: Arc<Metrics>,
+    sender: mio::Sender<Command>,
+    thread: Option<JoinHandle<io::Result<ShutdownSummary>>>
+}
+
+impl ServerHandle {
+    /// the address of the first tcp listener. panics if the server only listens on unix sockets
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs.iter().filter_map(|a| a.tcp()).next().expect("server has no tcp listener")
+    }
+
+    /// the addresses of every listener, in the order they were added
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.addrs
+    }
+
+    /// the port of the first tcp listener
+    pub fn port(&self) -> u16 {
+        self.local_addr().port()
+    }
+
+    /// the address metrics are served on, if they are
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// the server's counters, updated as it runs
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// replaces the ACL of the running server
+    pub fn set_acl(&self, acl: Acl) -> io::Result<()> {
+        self.sender.send(Command::SetAcl(Arc::new(acl)))
+            .map_err(|_| io::Error::other("the server is not running"))
+    }
+
+    /// stops the server gracefully and waits for it to drain
+    pub fn shutdown(mut self) -> io::Result<ShutdownSummary> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<ShutdownSummary> {
+        match self.thread.take() {
+            Some(thread) => {
+                // the event loop may already be gone, in which case join reports why
+                let _ = self.sender.send(Command::Shutdown);
+                thread.join().unwrap_or_else(|_| Err(io::Error::other("event loop panicked")))
+            }
+            None => Ok(ShutdownSummary::default())
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// a listening socket and the connections accepted through it
+struct Listener {
+    socket: Option<ListenSocket>, // None once shutting down
+    config: ListenerConfig,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    connections: usize
+}
+
+impl Listener {
+    fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self.socket {
+            Some(ref s) => s.local_addr(),
+            None => Ok(self.config.addr.clone())
+        }
+    }
+}
+
+struct RQueueServer {
+    listeners: Vec<Listener>, // indexed by token
+    clients: HashMap<Token, Client>, // just a regular slow hm for now
+    token_counter: usize,
+    settings: Settings,
+    summary: ShutdownSummary,
+    worker_pool: StatePool<RawMessage, Delivery>,
+
+    /// every subscription, changed only here and read by the workers
+    topics: Arc<TopicIndex>,
+
+    /// replaced when the ACL is reloaded
+    acl: Arc<Acl>,
+
+    /// checks AUTHs off the event loop, if there are credentials to check against
+    authenticator: Option<Authenticator>,
+
+    metrics: Arc<Metrics>,
+    started: Instant,
+
+    /// when the $SYS topics were last published, and the counters at the time, to derive rates
+    last_stats: (Instant, Snapshot),
+
+    /// the pending $SYS timer, cleared on shutdown
+    sys_timer: Option<mio::Timeout>,
+
+    /// admin requests that need to look at other clients, answered once read_client returns
+    admin_requests: Vec<(Request, RawMessage)>,
+
+    /// set by the workers when they ask to have paused publishers resumed, cleared once they are
+    wake_pending: Arc<AtomicBool>,
+
+    /// publishers holding a notification the workers had no room for, oldest first
+    paused: Vec<Token>,
+
+    /// set once shutting down. nothing more is read, clients only get to read what they are owed
+    closing: bool
+}
+
+impl RQueueServer {
+    /// accepts every pending connection on a listener. listeners are edge triggered so the
+    /// backlog has to be drained completely, otherwise simultaneous connects are never picked up
+    fn accept(&mut self, event_loop: &mut EventLoop<RQueueServer>, index: usize) {
+        loop {
+            let listener = &mut self.listeners[index];
+            let accepted = match listener.socket {
+                Some(ref socket) => socket.accept(),
+                None => return
+            };
+            let (client_socket, addr) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!(listener:% = listener.config.addr, error:% = e; "accept failed");
+                    return;
+                }
+            };
+            if self.clients.len() >= self.settings.max_connections {
+                warn!(addr = addr.as_str(), limit = self.settings.max_connections; "rejecting connection, server at its limit");
+                self.metrics.limited(Limit::Connections);
+                refuse(client_socket, listener.tls.is_none());
+                continue;
+            }
+            let listener_max = listener.config.max_connections.unwrap_or(usize::MAX);
+            if listener.connections >= listener_max {
+                warn!(addr = addr.as_str(), listener:% = listener.config.addr, limit = listener_max;
+                      "rejecting connection, listener at its limit");
+                self.metrics.limited(Limit::Connections);
+                refuse(client_socket, listener.tls.is_none());
+                continue;
+            }
+            let max_payload = listener.config.max_payload.unwrap_or(self.settings.max_payload);
+            // the handshake happens as the client is read from, like any other message
+            let client_socket = match listener.tls {
+                Some(ref config) => match TlsStream::new(config.clone(), client_socket) {
+                    Ok(tls) => Stream::Tls(Box::new(tls)),
+                    Err(e) => {
+                        warn!(addr = addr.as_str(), error:% = e; "could not start a tls session");
+                        continue;
+                    }
+                },
+                None => client_socket
+            };
+            //for now just increment the token (FAIP the client id)
+            self.token_counter += 1;
+            let ntoken = Token(self.token_counter);
+            debug!(conn = self.token_counter, addr = addr.as_str(), listener:% = listener.config.addr; "accepted");
+            let require_auth = self.settings.require_auth(&listener.config);
+            let mut client = Client::new(Peer::new(self.token_counter, client_socket, addr), index, max_payload, require_auth);
+            client.permissions = self.acl.permissions(None);
+            client.limiter = RateLimiter::new(&self.settings.rate_limit, Instant::now());
+
+            if event_loop.register(&client.evented(), ntoken, EventSet::readable(), PollOpt::edge()).is_ok() {
+                self.clients.insert(ntoken, client);
+                listener.connections += 1;
+                self.summary.connections_accepted += 1;
+                self.metrics.connected();
+            }
+        }
+    }
+
+    /// forgets about a client and purges its subscriptions, closing the socket
+    fn remove_client(&mut self, event_loop: &mut EventLoop<RQueueServer>, token: Token) {
+        debug!(conn = token.0; "removing");
+        if let Some(client) = self.clients.remove(&token) {
+            let _ = event_loop.deregister(&client.evented());
+            self.listeners[client.listener].connections -= 1;
+            self.metrics.disconnected();
+            for topic in client.subscriptions.iter() {
+                self.topics.unsubscribe(topic, token.0);
+            }
+            // workers may still hold on to the peer, so the connection is closed explicitly
+            client.peer.close();
+        }
+    }
+
+    /// reads every available message off of a client, handing them to the pool. fails if the
+    /// client has to be disconnected
+    fn read_client(&mut self, token: Token) -> io::Result<()> {
+        let client = self.clients.get_mut(&token).expect("read from an unknown client");
+        // replies are queued, so this stops as soon as too many of them pile up
+        while client.reading() {
+            let message = match get_message(&client.peer, &mut client.partial, client.max_payload)? {
+                Some(m) => m,
+                None => return Ok(())
+            };
+            self.summary.messages_received += 1;
+            self.metrics.received(message.length);
+
+            // limits apply to AUTH too, which slows down guessing
+            if let Err(limit) = client.limiter.admit(message.length, Instant::now()) {
+                self.metrics.limited(limit);
+                let reason = format!("over the {} per second limit, dropping messages", limit.name());
+                if !client.throttled {
+                    debug!(conn = token.0, limit = limit.name(); "rate limited");
+                    let _ = client.peer.queue(&error_message(ERR_RATE_LIMITED, &reason));
+                    client.throttled = true;
+                }
+                // every PUBLISH is answered, even while the client is told only once
+                if message.m_type == PUBLISH || message.m_type == PUBLISH_HEADERS {
+                    if let Some((seq, _)) = protocol::parse_publish(&message.bytes[PREAMBLE_SZ..message.length]) {
+                        let _ = client.peer.queue(&pubnack_message(seq, ERR_RATE_LIMITED, &reason));
+                    }
+                }
+                continue;
+            }
+            client.throttled = false;
+
+            if message.m_type == AUTH {
+                let payload = &message.bytes[PREAMBLE_SZ..message.length];
+                match (protocol::parse_auth(payload), self.authenticator.as_ref()) {
+                    (Some((name, secret)), Some(authenticator)) => {
+                        // reading resumes once the answer is in, see authenticated
+                        authenticator.check(token.0, name, secret);
+                        client.authenticating = true;
+                        return Ok(());
+                    }
+                    // nothing to check against, so any AUTH goes
+                    (Some(_), None) => client.admit(token, None, &self.acl)?,
+                    (None, _) => return Err(client.reject(ERR_AUTH_FAILED, "malformed AUTH"))
+                }
+                continue;
+            }
+            if client.require_auth && !client.authenticated {
+                return Err(client.reject(ERR_AUTH_REQUIRED, "authentication required"));
+            }
+
+            // from here on a PUBLISH is the NOTIFICATION it carries, and a PUBLISH_HEADERS the
+            // HEADERS, remembering what to confirm
+            let message = match message.m_type {
+                PUBLISH | PUBLISH_HEADERS => match message.published() {
+                    Some(notification) => notification,
+                    None => {
+                        let _ = client.peer.queue(&error_message(ERR_BAD_REQUEST, "malformed PUBLISH"));
+                        continue;
+                    }
+                },
+                _ => message
+            };
+
+            let payload = &message.bytes[PREAMBLE_SZ..message.length];
+            if message.m_type == FEATURES {
+                let features = payload.first().cloned().unwrap_or(0);
+                debug!(conn = token.0, features = features; "features");
+                client.peer.set_features(features);
+                continue;
+            }
+            // the workers trust the header block, so it is checked here
+            if message.m_type == HEADERS && protocol::split_headers(payload).and_then(|(_, block, _)| protocol::parse_headers(block)).is_none() {
+                let _ = match message.seq {
+                    Some(seq) => client.peer.queue(&pubnack_message(seq, ERR_BAD_REQUEST, "malformed HEADERS")),
+                    None => client.peer.queue(&error_message(ERR_BAD_REQUEST, "malformed HEADERS"))
+                };
+                continue;
+            }
+            if message.m_type == ADMIN {
+                match Request::parse(payload) {
+                    _ if !client.permissions.admin => {
+                        let _ = client.peer.queue(&error_message(ERR_PERMISSION_DENIED, "not an admin"));
+                    }
+                    None => {
+                        let _ = client.peer.queue(&error_message(ERR_BAD_REQUEST, "malformed ADMIN"));
+                    }
+                    Some(request) => {
+                        // answered before reading on, so that the replies count towards MAX_QUEUED
+                        self.admin_requests.push((request, message));
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+            // subscriptions are kept here, only notifications are handed to the workers
+            match message.m_type {
+                // nothing could ever be published to it
+                SUBSCRIBE if payload.len() > MAX_TOPIC_SZ => {
+                    let reason = format!("topics are at most {} bytes", MAX_TOPIC_SZ);
+                    let _ = client.peer.queue(&error_message(ERR_BAD_REQUEST, &reason));
+                }
+                SUBSCRIBE if !client.permissions.may_subscribe(payload) => client.deny("subscribe to", payload),
+                SUBSCRIBE if client.subscriptions.contains(payload) => (),
+                SUBSCRIBE if client.subscriptions.len() >= self.settings.max_subscriptions => {
+                    self.metrics.limited(Limit::Subscriptions);
+                    let reason = format!("at the limit of {} subscriptions", self.settings.max_subscriptions);
+                    let _ = client.peer.queue(&error_message(ERR_TOO_MANY_SUBSCRIPTIONS, &reason));
+                }
+                SUBSCRIBE => {
+                    trace!(conn = token.0, topic:% = String::from_utf8_lossy(payload); "subscribed");
+                    client.subscriptions.insert(payload.to_vec());
+                    self.topics.subscribe(payload, client.peer.clone());
+                }
+                REMOVE => if client.subscriptions.remove(payload) {
+                    trace!(conn = token.0, topic:% = String::from_utf8_lossy(payload); "unsubscribed");
+                    self.topics.unsubscribe(payload, token.0);
+                },
+                DEREGISTER => for topic in client.subscriptions.drain() {
+                    self.topics.unsubscribe(&topic, token.0);
+                },
+                NOTIFICATION | HEADERS => match protocol::notification_topic(payload) {
+                    //defers work to the pool
+                    Some(topic) if !protocol::is_reserved(topic) && client.permissions.may_publish(topic) => {
+                        if let Some(message) = hand_off(&mut self.worker_pool, client, self.settings.queue_depth, message) {
+                            // the rest stays in the socket until the workers catch up
+                            debug!(conn = token.0, in_flight = client.in_flight; "pausing");
+                            client.held = Some(message);
+                            self.paused.push(token);
+                            return Ok(());
+                        }
+                    }
+                    Some(topic) => match message.seq {
+                        Some(seq) => {
+                            let reason = format!("not allowed to publish to {}", String::from_utf8_lossy(topic));
+                            let _ = client.peer.queue(&pubnack_message(seq, ERR_PERMISSION_DENIED, &reason));
+                        }
+                        None => client.deny("publish to", topic)
+                    },
+                    None => match message.seq {
+                        Some(seq) => {
+                            let _ = client.peer.queue(&pubnack_message(seq, ERR_BAD_REQUEST, "malformed PUBLISH"));
+                        }
+                        None => client.deny("publish to", b"")
+                    }
+                },
+                m_type => warn!(conn = token.0, m_type = m_type; "unexpected message type")
+            }
+        }
+        Ok(())
+    }
+
+    /// answers the admin requests queued up by read_client
+    fn run_admin(&mut self, event_loop: &mut EventLoop<RQueueServer>) {
+        for (request, message) in mem::take(&mut self.admin_requests) {
+            let peer = message.peer.clone().expect("admin requests come from clients");
+            let frames = match request {
+                Request::ListClients => {
+                    let mut clients = self.clients.values().map(|c| ClientInfo {
+                        id: c.peer.id(),
+                        addr: c.peer.addr().to_owned(),
+                        user: c.user.as_ref().map(|u| u.name.clone()),
+                        subscriptions: c.subscriptions.len(),
+                        in_flight: c.in_flight,
+                        delivered: c.delivered
+                    }).collect::<Vec<_>>();
+                    clients.sort_by_key(|c| c.id);
+                    admin::reply_frames(admin::LIST_CLIENTS, &clients)
+                }
+                Request::ListTopics => {
+                    let mut topics = self.topics.topics().into_iter()
+                        .map(|(topic, subscribers)| TopicInfo { topic, subscribers })
+                        .collect::<Vec<_>>();
+                    topics.sort_by(|a, b| a.topic.cmp(&b.topic));
+                    admin::reply_frames(admin::LIST_TOPICS, &topics)
+                }
+                Request::Subscriptions(id) | Request::Disconnect(id) if !self.clients.contains_key(&Token(id)) => {
+                    vec![error_message(ERR_NOT_FOUND, &format!("no client {}", id))]
+                }
+                Request::Subscriptions(id) => {
+                    let mut topics = self.clients[&Token(id)].subscriptions.iter().cloned().collect::<Vec<_>>();
+                    topics.sort();
+                    admin::reply_frames(admin::SUBSCRIPTIONS, &topics)
+                }
+                Request::Disconnect(id) => {
+                    info!(conn = id, admin = peer.id(); "disconnecting, as asked by an admin");
+                    self.remove_client(event_loop, Token(id));
+                    admin::reply_frames::<ClientInfo>(admin::DISCONNECT, &[])
+                }
+            };
+            // written out along with the other replies, see serve_client
+            for frame in frames.iter() {
+                if peer.queue(frame).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// swaps in a new ACL, removing every subscription that it no longer allows
+    fn set_acl(&mut self, event_loop: &mut EventLoop<RQueueServer>, acl: Arc<Acl>) {
+        info!("reloaded the acl");
+        self.acl = acl;
+        for client in self.clients.values_mut() {
+            client.permissions = self.acl.permissions(client.user.as_ref().map(|u| u.name.as_str()));
+            let revoked = client.subscriptions.iter()
+                .filter(|topic| !client.permissions.may_subscribe(topic))
+                .cloned()
+                .collect::<Vec<_>>();
+            for topic in revoked {
+                client.deny("subscribe to", &topic);
+                client.subscriptions.remove(&topic);
+                self.topics.unsubscribe(&topic, client.peer.id());
+            }
+        }
+        let tokens = self.clients.keys().cloned().collect::<Vec<_>>();
+        for token in tokens {
+            self.watch(event_loop, token);
+        }
+    }
+
+    /// writes out the replies queued for a client and reads what it sent, then watches it again
+    /// unless it is gone
+    fn serve_client(&mut self, event_loop: &mut EventLoop<RQueueServer>, token: Token) {
+        if self.closing {
+            return self.flush_client(event_loop, token);
+        }
+        let mut served = self.clients[&token].peer.flush();
+        while served.is_ok() {
+            served = self.read_client(token);
+            if self.admin_requests.is_empty() {
+                break;
+            }
+            self.run_admin(event_loop);
+            // the client may have disconnected itself
+            if !self.clients.contains_key(&token) {
+                break;
+            }
+        }
+        match served {
+            Ok(()) => self.watch(event_loop, token),
+            Err(e) => {
+                // clients leaving is business as usual
+                match e.kind() {
+                    io::ErrorKind::UnexpectedEof => debug!(conn = token.0, reason:% = e; "dropping"),
+                    _ => info!(conn = token.0, reason:% = e; "dropping")
+                }
+                self.remove_client(event_loop, token);
+            }
+        }
+    }
+
+    /// writes out what a client is owed once the server is shutting down, and stops the event
+    /// loop once every client has read its replies
+    fn flush_client(&mut self, event_loop: &mut EventLoop<RQueueServer>, token: Token) {
+        match self.clients[&token].peer.flush() {
+            Ok(()) => self.watch(event_loop, token),
+            Err(e) => {
+                debug!(conn = token.0, reason:% = e; "dropping");
+                self.remove_client(event_loop, token);
+            }
+        }
+        self.stop_once_flushed(event_loop);
+    }
+
+    fn stop_once_flushed(&mut self, event_loop: &mut EventLoop<RQueueServer>) {
+        if self.clients.values().all(|client| client.peer.is_flushed()) {
+            event_loop.shutdown();
+        }
+    }
+
+    /// watches a client for more messages, unless reading from it is paused, and for room in the
+    /// socket buffer while it has replies queued. the client may have disconnected itself through
+    /// an admin request
+    fn watch(&self, event_loop: &mut EventLoop<RQueueServer>, token: Token) {
+        if let Some(client) = self.clients.get(&token) {
+            let mut events = EventSet::hup();
+            if client.reading() && !self.closing {
+                events = events | EventSet::readable();
+            }
+            if !client.peer.is_flushed() {
+                events = events | EventSet::writable();
+            }
+            let _ = event_loop.reregister(&client.evented(), token, events, PollOpt::edge());
+        }
+    }
+
+    /// lets a client the Authenticator has checked in as {user}, and reads the rest of what it
+    /// sent, or disconnects it if its credentials were bad
+    fn authenticated(&mut self, event_loop: &mut EventLoop<RQueueServer>, token: Token, user: Option<Arc<User>>) {
+        // the client may have gone away meanwhile
+        let client = match self.clients.get_mut(&token) {
+            Some(client) if client.authenticating => client,
+            _ => return
+        };
+        client.authenticating = false;
+        let admitted = match user {
+            Some(user) => client.admit(token, Some(user), &self.acl),
+            None => Err(client.reject(ERR_AUTH_FAILED, "invalid credentials"))
+        };
+        match admitted {
+            Ok(()) => self.serve_client(event_loop, token),
+            Err(e) => {
+                info!(conn = token.0, reason:% = e; "dropping");
+                self.remove_client(event_loop, token);
+            }
+        }
+    }
+
+    /// hands the workers the notifications publishers were paused on, and reads from those
+    /// publishers again, for as long as there is room
+    fn resume_publishers(&mut self, event_loop: &mut EventLoop<RQueueServer>) {
+        for token in mem::take(&mut self.paused) {
+            // the client may have gone away meanwhile
+            let client = match self.clients.get_mut(&token) {
+                Some(client) => client,
+                None => continue
+            };
+            let message = client.held.take().expect("paused publishers hold a notification");
+            if let Some(message) = hand_off(&mut self.worker_pool, client, self.settings.queue_depth, message) {
+                // the workers will say when to try again
+                client.held = Some(message);
+                self.paused.push(token);
+                continue;
+            }
+            debug!(conn = token.0; "resuming");
+            // the socket won't signal what arrived while it was paused
+            self.serve_client(event_loop, token);
+        }
+    }
+
+    /// accounts for what the workers made of the notifications they were handed
+    fn collect_results(&mut self, event_loop: &mut EventLoop<RQueueServer>) {
+        let mut answered = HashSet::new();
+        while let Ok(delivery) = self.worker_pool.wait_rx.try_recv() {
+            if delivery.publisher.is_some() && delivery.error.is_none() && delivery.subscribers == 0 {
+                self.metrics.unrouted();
+            }
+            // the server's own, or from a client that is gone
+            let client = match delivery.publisher.and_then(|id| self.clients.get_mut(&Token(id))) {
+                Some(client) => client,
+                None => continue
+            };
+            client.in_flight -= 1;
+            client.delivered += delivery.delivered as u64;
+            if let Some(error) = delivery.error {
+                warn!(conn = client.peer.id(), error = error; "notification not routed");
+            }
+            // nothing is persisted, so a PUBLISH is done with once it has been fanned out
+            let _ = match (delivery.seq, delivery.error) {
+                (Some(seq), None) => client.peer.queue(&puback_message(seq)),
+                (Some(seq), Some(error)) => client.peer.queue(&pubnack_message(seq, ERR_INTERNAL, error)),
+                (None, _) => continue
+            };
+            answered.insert(Token(client.peer.id()));
+        }
+        for token in answered {
+            self.watch(event_loop, token);
+        }
+    }
+
+    /// publishes server statistics on the $SYS topics. rates are per second since the last time
+    fn publish_stats(&mut self) {
+        let now = Instant::now();
+        let current = self.metrics.snapshot();
+        let (then, last) = self.last_stats;
+        let secs = now.duration_since(then).as_secs_f64().max(0.001);
+        let rate = |current: u64, last: u64| format!("{:.1}", current.saturating_sub(last) as f64 / secs);
+
+        let stats = [
+            ("uptime", self.started.elapsed().as_secs().to_string()),
+            ("clients", current.connections.to_string()),
+            ("topics", current.topics.to_string()),
+            ("messages.received", rate(current.messages_received, last.messages_received)),
+            ("messages.delivered", rate(current.messages_delivered, last.messages_delivered)),
+            ("bytes.received", rate(current.bytes_received, last.bytes_received)),
+            ("bytes.delivered", rate(current.bytes_delivered, last.bytes_delivered))
+        ];
+        for (name, value) in stats.iter() {
+            let topic = [protocol::SYS_PREFIX, name.as_bytes()].concat();
+            // statistics are not worth holding anyone up for
+            if self.worker_pool.send_rr(RawMessage::from_frame(&notify_message(&topic, value.as_bytes()), None)).is_err() {
+                debug!(topic:% = String::from_utf8_lossy(&topic); "worker queues full, skipping");
+            }
+        }
+        self.last_stats = (now, current);
+    }
+
+    /// stops accepting, lets the workers deliver what they were already handed, answers the
+    /// publishers, then tells the clients (if configured to) and stops the event loop once they
+    /// have read all of it, or the drain timeout runs out again
+    fn shutdown(&mut self, event_loop: &mut EventLoop<RQueueServer>) {
+        if self.closing {
+            return;
+        }
+        self.closing = true;
+        if let Some(timer) = self.sys_timer.take() {
+            event_loop.clear_timeout(timer);
+        }
+        for listener in self.listeners.iter_mut() {
+            if let Some(socket) = listener.socket.take() {
+                let _ = event_loop.deregister(&EventedFd(&socket.as_raw_fd()));
+                socket.cleanup();
+            }
+        }
+        info!(drain_timeout:? = self.settings.drain_timeout; "shutting down, draining workers");
+
+        let start = Instant::now();
+        let drained = self.worker_pool.shutdown(self.settings.drain_timeout);
+        self.summary.drain_time = start.elapsed();
+        self.summary.workers_drained = drained;
+        self.summary.workers_abandoned = self.worker_pool.workers.len() - drained;
+        self.summary.connections_open = self.clients.len();
+
+        // publishers hear back about what the workers got through, and about what they never got
+        self.collect_results(event_loop);
+        self.paused.clear();
+        for client in self.clients.values_mut() {
+            if let Some(seq) = client.held.take().and_then(|message| message.seq) {
+                let _ = client.peer.queue(&pubnack_message(seq, ERR_INTERNAL, "the server is shutting down"));
+            }
+        }
+
+        if self.settings.notify_shutdown {
+            let frame = shutdown_message();
+            for client in self.clients.values() {
+                let _ = client.peer.queue(&frame);
+            }
+        }
+
+        // from here on clients are only written to, see serve_client
+        let tokens = self.clients.keys().cloned().collect::<Vec<_>>();
+        for token in tokens {
+            self.watch(event_loop, token);
+        }
+        if event_loop.timeout_ms(Timer::Stop, self.settings.drain_timeout.as_millis() as u64).is_err() {
+            event_loop.shutdown();
+        }
+        self.stop_once_flushed(event_loop);
+    }
+}
+
+// implements a vanilla-ish mio event loop
+impl Handler for RQueueServer {
+    type Timeout = Timer;
+    type Message = Command;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<RQueueServer>, token: Token, events: EventSet) {
+        match token {
+            Token(i) if i < self.listeners.len() => {
+                assert!(events.is_readable());
+                self.accept(event_loop, i);
+            }
+            token => {
+                if events.is_hup() { // on client hangup
+                    self.remove_client(event_loop, token);
+                    if self.closing {
+                        self.stop_once_flushed(event_loop);
+                    }
+                } else if self.clients.contains_key(&token) {
+                    // readable, writable or both
+                    self.serve_client(event_loop, token);
+                }
+            }
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<RQueueServer>, timer: Timer) {
+        match timer {
+            Timer::SysStats => {
+                if self.sys_timer.is_none() {
+                    return; // shutting down
+                }
+                self.publish_stats();
+                let interval = self.settings.sys_interval.as_millis() as u64;
+                self.sys_timer = event_loop.timeout_ms(Timer::SysStats, interval).ok();
+            }
+            Timer::Stop => {
+                let owed = self.clients.values().filter(|client| !client.peer.is_flushed()).count();
+                info!(clients = owed; "dropping clients that did not read what they are owed");
+                event_loop.shutdown();
+            }
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<RQueueServer>, msg: Command) {
+        match msg {
+            Command::Shutdown => self.shutdown(event_loop),
+            Command::SetAcl(acl) => self.set_acl(event_loop, acl),
+            Command::Progress => {
+                self.wake_pending.store(false, Ordering::Release);
+                // workers that died woke us up too
+                self.worker_pool.supervise();
+                self.collect_results(event_loop);
+                self.resume_publishers(event_loop);
+            }
+            Command::Authenticated(token, user) => self.authenticated(event_loop, token, user)
+        }
+    }
+}
+
+/// hands {message} to the workers, unless {client} already has {cap} notifications with them, so
+/// that one publisher can't take up every queue, or there is no room. otherwise it is handed back
+fn hand_off(pool: &mut StatePool<RawMessage, Delivery>, client: &mut Client, cap: usize, message: RawMessage)
+            -> Option<RawMessage> {
+    if client.in_flight >= cap {
+        return Some(message);
+    }
+    let held = pool.send_rr(message).err();
+    if held.is_none() {
+        client.in_flight += 1;
+    }
+    held
+}
+
+/// tells a connection that is turned away why, unless it is expecting a TLS handshake. best
+/// effort, the frame is tiny so it should fit in the socket buffer
+fn refuse(mut socket: Stream, plaintext: bool) {
+    if plaintext {
+        let _ = socket.write(&error_message(ERR_TOO_MANY_CONNECTIONS, "too many connections"));
+    }
+}
+
+/// wrapper over client connections
+struct Client {
+    peer: Arc<Peer>,
+
+    /// the frame being read, until all of it has arrived
+    partial: PartialFrame,
+
+    /// index of the listener the client connected through
+    listener: usize,
+    max_payload: usize,
+    require_auth: bool,
+
+    /// set once an AUTH succeeds
+    authenticated: bool,
+
+    /// set while the Authenticator checks the client's AUTH. nothing more is read from the client
+    /// until it has
+    authenticating: bool,
+
+    /// who the client authenticated as, if there were credentials to check against
+    user: Option<Arc<User>>,
+
+    /// what the ACL allows the client to do
+    permissions: Arc<Permissions>,
+
+    /// topics the client is subscribed to, so that they can be revoked when the ACL changes
+    subscriptions: HashSet<Vec<u8>>,
+
+    limiter: RateLimiter,
+
+    /// set while messages are being dropped, so the client is only told once per bout
+    throttled: bool,
+
+    /// a notification the workers had no room for. nothing more is read from the client until it
+    /// has been handed over
+    held: Option<RawMessage>,
+
+    /// notifications handed to the workers that they have not reported on yet
+    in_flight: usize,
+
+    /// writes of the client's notifications to subscribers
+    delivered: u64
+}
+
+impl Client {
+    fn new(peer: Peer, listener: usize, max_payload: usize, require_auth: bool) -> Client {
+        Client {
+            peer: Arc::new(peer),
+            partial: PartialFrame::new(),
+            listener,
+            max_payload,
+            require_auth,
+            authenticated: false,
+            authenticating: false,
+            user: None,
+            permissions: Arc::new(Permissions::default()),
+            subscriptions: HashSet::new(),
+            limiter: RateLimiter::new(&RateLimit::default(), Instant::now()),
+            throttled: false,
+            held: None,
+            in_flight: 0,
+            delivered: 0
+        }
+    }
+
+    /// the client socket, for (de)registering with the event loop
+    fn evented(&self) -> EventedFd<'_> {
+        EventedFd(self.peer.fd())
+    }
+
+    /// whether to read from the client: not while its AUTH is checked, while it holds a
+    /// notification the workers had no room for, or while more than MAX_QUEUED bytes of replies
+    /// wait for it to read them
+    fn reading(&self) -> bool {
+        !self.authenticating && self.held.is_none() && self.peer.queued() <= MAX_QUEUED
+    }
+
+    /// lets the client in as {user}, with what {acl} allows them
+    fn admit(&mut self, token: Token, user: Option<Arc<User>>, acl: &Acl) -> io::Result<()> {
+        debug!(conn = token.0, user:? = user.as_ref().map(|u| &u.name); "authenticated");
+        self.permissions = acl.permissions(user.as_ref().map(|u| u.name.as_str()));
+        self.user = user;
+        self.authenticated = true;
+        self.peer.queue(&auth_ok_message())
+    }
+
+    /// tells the client why it is about to be disconnected, returns the reason as an error
+    fn reject(&self, code: u8, reason: &str) -> io::Error {
+        let _ = self.peer.queue(&error_message(code, reason));
+        io::Error::new(io::ErrorKind::PermissionDenied, reason)
+    }
+
+    /// tells the client that the ACL does not allow it to {action} {topic}
+    fn deny(&self, action: &str, topic: &[u8]) {
+        let reason = format!("not allowed to {} {}", action, String::from_utf8_lossy(topic));
+        let _ = self.peer.queue(&error_message(ERR_PERMISSION_DENIED, &reason));
+    }
+}
@@ -0,0 +1,40 @@
+use std::{io, mem, ptr};
+use libc;
+
+pub use libc::{c_int, SIGINT, SIGTERM, SIGHUP};
+
+/// a set of signals that are blocked and picked up synchronously with sigwait, so that nothing
+/// has to happen inside of an async signal handler
+pub struct SignalSet {
+    set: libc::sigset_t
+}
+
+impl SignalSet {
+    /// blocks {signals} on the calling thread. threads inherit the mask of the thread that spawns
+    /// them, so call this before starting the server to keep the signals away from its threads
+    pub fn block (signals: &[c_int]) -> io::Result<SignalSet> {
+        let set = unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                if libc::sigaddset(&mut set, *signal) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            set
+        };
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) } {
+            0 => Ok(SignalSet { set }),
+            e => Err(io::Error::from_raw_os_error(e))
+        }
+    }
+
+    /// blocks until one of the signals in the set is delivered, and returns it
+    pub fn wait (&self) -> io::Result<c_int> {
+        let mut signal = 0;
+        match unsafe { libc::sigwait(&self.set, &mut signal) } {
+            0 => Ok(signal),
+            e => Err(io::Error::from_raw_os_error(e))
+        }
+    }
+}
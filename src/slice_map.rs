@@ -1,59 +1,204 @@
-use std::{ptr, mem};
-use std::hash::{SipHasher, Hash, Hasher};
+#![forbid(unsafe_code)]
 
-/// size of static array
+use std::{array, fmt, mem, slice, vec};
+use std::collections::hash_map::{RandomState, DefaultHasher};
+use std::convert::TryInto;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::str::FromStr;
+
+/// size of static array, and the fewest buckets a map ever has
 const STATIC_SZ: usize = 2048;
 
+/// the table doubles once it holds more than MAX_LOAD_NUM / MAX_LOAD_DEN entries per bucket
+const MAX_LOAD_NUM: usize = 3;
+const MAX_LOAD_DEN: usize = 4;
+
+/// and halves once it falls under 1 / MIN_LOAD_DEN, far enough below the growth threshold that
+/// a map hovering around a size doesn't keep rehashing
+const MIN_LOAD_DEN: usize = 8;
+
+#[allow(clippy::large_enum_variant)]
 enum InlineVec <T> {
-    Static(usize, [Vec<T>; STATIC_SZ]), //this is kind of silly, probably should just use vecs
+    Static([Vec<T>; STATIC_SZ]), //this is kind of silly, probably should just use vecs
     Dynamic(Vec<Vec<T>>)
 }
 
+/// the hash rustc uses for its own tables. it is fast on short keys like topics, but easy to find
+/// collisions for, so it should only hash keys from trusted sources
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FxHasher {
+    hash: u64
+}
+
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FxHasher {
+    fn add (&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write (&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.add(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        let rest = words.remainder();
+        if !rest.is_empty() {
+            let mut word = [0; 8];
+            word[..rest.len()].copy_from_slice(rest);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_usize (&mut self, n: usize) {
+        self.add(n as u64);
+    }
+
+    /// the multiplies leave the low bits, which pick the bucket, depending on little of the input.
+    /// murmur3's finalizer mixes every bit into them
+    fn finish (&self) -> u64 {
+        let mut h = self.hash;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+}
+
+pub type FxBuildHasher = BuildHasherDefault<FxHasher>;
+
+/// a hash picked at runtime, e.g. from configuration
+#[derive(Debug, Clone)]
+pub enum Hashing {
+    /// FxHasher, for deployments where clients are trusted
+    Fast,
+
+    /// SipHash with random keys, so that clients can't pick topics that all land in one bucket
+    Keyed(RandomState)
+}
+
+impl Hashing {
+    /// SipHash with freshly generated keys
+    pub fn keyed () -> Hashing {
+        Hashing::Keyed(RandomState::new())
+    }
+}
+
+impl FromStr for Hashing {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Hashing, String> {
+        match s {
+            "fast" => Ok(Hashing::Fast),
+            "keyed" => Ok(Hashing::keyed()),
+            _ => Err(format!("unknown hash {:?}, expected fast or keyed", s))
+        }
+    }
+}
+
+impl fmt::Display for Hashing {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Hashing::Fast => "fast",
+            Hashing::Keyed(_) => "keyed"
+        })
+    }
+}
+
+/// the hasher of a Hashing
+pub enum HashingHasher {
+    Fast(FxHasher),
+    Keyed(DefaultHasher)
+}
+
+impl Hasher for HashingHasher {
+    fn write (&mut self, bytes: &[u8]) {
+        match *self {
+            HashingHasher::Fast(ref mut h) => h.write(bytes),
+            HashingHasher::Keyed(ref mut h) => h.write(bytes)
+        }
+    }
+
+    fn write_usize (&mut self, n: usize) {
+        match *self {
+            HashingHasher::Fast(ref mut h) => h.write_usize(n),
+            HashingHasher::Keyed(ref mut h) => h.write_usize(n)
+        }
+    }
+
+    fn finish (&self) -> u64 {
+        match *self {
+            HashingHasher::Fast(ref h) => h.finish(),
+            HashingHasher::Keyed(ref h) => h.finish()
+        }
+    }
+}
+
+impl BuildHasher for Hashing {
+    type Hasher = HashingHasher;
+
+    fn build_hasher (&self) -> HashingHasher {
+        match *self {
+            Hashing::Fast => HashingHasher::Fast(FxHasher::default()),
+            Hashing::Keyed(ref state) => HashingHasher::Keyed(state.build_hasher())
+        }
+    }
+}
+
 /// A specialized version of a HashMap
 /// lookups can be done on byte slices without allocations
 /// keys are stored as vectors
 /// collisions are handled by chaining on vectors
-pub struct SliceMap <V> {
+/// the table starts out as a static array and moves to the heap the first time it grows
+/// keys are hashed with {S}, FxHasher unless another is given to with_hasher
+pub struct SliceMap <V, S = FxBuildHasher> {
     count: usize,
     table: InlineVec<HashEntry<V>>,
-    capacity: usize
+
+    /// the number of buckets, always a power of two
+    capacity: usize,
+
+    hasher: S
 }
 
-//TODO: handle resizing
+impl<V, S: BuildHasher + Default> Default for SliceMap <V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
 
 impl <V> SliceMap <V> {
     pub fn new () -> SliceMap <V> {
-        let stat = unsafe {
-            let mut stat:[Vec<HashEntry<V>>; STATIC_SZ] = mem::uninitialized();
-            for i in stat.iter_mut() {
-                ptr::write(i, Vec::new());
-            }
-            stat
-        };
+        Self::with_hasher(FxBuildHasher::default())
+    }
+}
+
+impl <V, S: BuildHasher> SliceMap <V, S> {
+    /// an empty map that hashes keys with {hasher}
+    pub fn with_hasher (hasher: S) -> SliceMap <V, S> {
         SliceMap {
             count: 0,
-            table: InlineVec::Static(0, stat),
-            capacity: STATIC_SZ
+            table: InlineVec::Static(array::from_fn(|_| Vec::new())),
+            capacity: STATIC_SZ,
+            hasher
         }
     }
 
     /// inserts a value to the map
     pub fn insert (&mut self, key: &[u8], val: V) {
-        let mut tab = match self.table {
-            InlineVec::Static(_, ref mut arr) => &mut arr[..],
-            InlineVec::Dynamic(ref mut vec) => &mut vec[..]
-        };
-
-        let hash = Self::make_hash(key);
+        let hash = self.make_hash(key);
         let index = hash & (self.capacity - 1);
-        let ref mut list = tab[index];
+        let list = &mut self.table_mut()[index];
 
         for i in list.iter_mut() {
-            if i.key == key {
+            if i.hash == hash && i.key == key {
                 *i = HashEntry {
                     key: key.to_owned(),
-                    val: val,
-                    hash: hash.clone()
+                    val,
+                    hash
                 };
                 return;
             }
@@ -61,69 +206,56 @@ impl <V> SliceMap <V> {
 
         list.push(HashEntry {
             key: key.to_owned(),
-            val: val,
-            hash: hash
+            val,
+            hash
         });
 
         self.count += 1;
-
+        self.grow();
     }
 
     /// get an immutable value from the map if it exists
     pub fn get (&self, key: &[u8]) -> Option<&V> {
+        self.get_hashed(self.hasher.hash_one(key), key)
+    }
+
+    /// {get} for a key the caller already hashed with {hasher}, to use the hash for something
+    /// else as well. any other hash finds nothing
+    pub fn get_hashed (&self, hash: u64, key: &[u8]) -> Option<&V> {
         if self.count == 0 {
-            None
-        } else {
-            let tab = match self.table {
-                InlineVec::Static(_, ref arr) => (&arr[..]),
-                InlineVec::Dynamic(ref vec) => (&vec[..])
-            };
-            let hash = Self::make_hash(key);
-            let index = hash & (self.capacity - 1);
-            match tab[index].iter().find(|e| &e.key[..] == key) {
-                None => None,
-                Some(s) => {
-                    let as_raw_ptr = &s.val as *const V;
-                    //return some questionably unsafe dereferenced raw pointer
-                    Some(unsafe{&*as_raw_ptr})
-                }
-            }
+            return None;
         }
+        let (index, position) = self.find(hash as usize, key)?;
+        Some(&self.table()[index][position].val)
     }
 
     /// modifies a {value} located at {key} with {mod_func} if it exists, else inserts a
     /// value into its place wrapped by {put_func}
     pub fn modify_or_else <F1, F2> (&mut self, key: &[u8], mod_func: F1, put_func: F2)
     where F1: Fn(&mut V), F2: FnOnce() -> V {
-        let hash = Self::make_hash(key);
+        let hash = self.make_hash(key);
         let index = hash & (self.capacity - 1);
-        let mut tab = match self.table {
-            InlineVec::Static(_, ref mut arr) => &mut arr[..],
-            InlineVec::Dynamic(ref mut vec) => &mut vec[..]
-        };
+        let list = &mut self.table_mut()[index];
 
-        if let Some(s) = tab[index].iter_mut().find(|e| &e.key[..] == key) {
+        if let Some(s) = list.iter_mut().find(|e| e.hash == hash && &e.key[..] == key) {
             mod_func(&mut s.val);
             return
         }
-        self.count += 1;
-        tab[index].push(HashEntry{
-            hash: hash,
+        list.push(HashEntry{
+            hash,
             key: key.to_owned(),
             val: put_func()
         });
+        self.count += 1;
+        self.grow();
     }
 
     /// modifies the val for the key.
     /// returns a flag (e.g. to be used if the val is considered empty etc)
     pub fn modify <F1, E> (&mut self, key: &[u8], mod_func: F1) -> Option<E> where F1: Fn(&mut V) -> Option<E> {
-        let hash = Self::make_hash(key);
+        let hash = self.make_hash(key);
         let index = hash & (self.capacity - 1);
-        let mut tab = match self.table {
-            InlineVec::Static(_, ref mut arr) => &mut arr[..],
-            InlineVec::Dynamic(ref mut vec) => &mut vec[..]
-        };
-        if let Some(s) = tab[index].iter_mut().find(|e| &e.key[..] == key) {
+        if let Some(s) = self.table_mut()[index].iter_mut().find(|e| e.hash == hash && &e.key[..] == key) {
             mod_func(&mut s.val)
         } else {
             None
@@ -132,61 +264,580 @@ impl <V> SliceMap <V> {
 
     /// removes an entry from the map, returns a bool if successful or not
     pub fn delete (&mut self, key: &[u8]) -> bool {
+        self.remove(key).is_some()
+    }
+
+    /// removes an entry from the map, returning its value if there was one
+    pub fn remove (&mut self, key: &[u8]) -> Option<V> {
         if self.count == 0 {
-            false
-        } else {
-            let mut tab = match self.table {
-                InlineVec::Static(_, ref mut arr) => &mut arr[..],
-                InlineVec::Dynamic(ref mut vec) => &mut vec[..]
-            };
-            let hash = Self::make_hash(key);
-            let index = hash & (self.capacity - 1);
-            let del_opt = tab[index].iter().enumerate().find(|&(_, e)| &e.key[..] == key).map(|(i, _)| i);
-            match del_opt {
-                None => false,
-                Some(index_to_delete) => {
-                    tab[index].remove(index_to_delete);
-                    self.count -= 1;
-                    true
-                }
-            }
+            return None;
         }
+        let hash = self.make_hash(key);
+        let (index, position) = self.find(hash, key)?;
+        Some(self.remove_at(index, position))
     }
 
     /// applies a function to a value in the hash table, mutably
     pub fn apply <F> (&mut self, key: &[u8], func: F) where F: Fn(&mut V) {
         if self.count == 0 {
-            return
         } else {
-            let hash = Self::make_hash(key);
-            let mut tab = match self.table {
-                InlineVec::Static(_, ref mut arr) => (&mut arr[..]),
-                InlineVec::Dynamic(ref mut vec) => (&mut vec[..])
-            };
+            let hash = self.make_hash(key);
             let index = hash & (self.capacity - 1);
 
-            match tab[index].iter_mut().find(|e| &e.key[..] == key) {
+            match self.table_mut()[index].iter_mut().find(|e| e.hash == hash && &e.key[..] == key) {
                 None => (),
-                Some(mut s) => {
+                Some(s) => {
                     func(&mut s.val);
                 }
             };
         }
     }
 
-    /// for now use SipHasher. in the future it may prove too slow. we don't really
-    /// need crypto security
-    fn make_hash (key: &[u8]) -> usize {
-        let mut s = SipHasher::new();
-        key.hash(&mut s);
-        s.finish() as usize
+    /// a mutable value from the map if it exists
+    pub fn get_mut (&mut self, key: &[u8]) -> Option<&mut V> {
+        let hash = self.make_hash(key);
+        let (index, position) = self.find(hash, key)?;
+        Some(&mut self.table_mut()[index][position].val)
+    }
+
+    pub fn contains_key (&self, key: &[u8]) -> bool {
+        self.find(self.make_hash(key), key).is_some()
     }
 
+    /// the entry for {key}, to be looked at, changed or filled in with a single lookup. the key is
+    /// only copied if a value is inserted
+    pub fn entry<'a> (&'a mut self, key: &'a [u8]) -> Entry<'a, V, S> {
+        let hash = self.hasher.hash_one(key);
+        self.entry_hashed(hash, key)
+    }
+
+    /// {entry} for a key the caller already hashed with {hasher}, see {get_hashed}
+    pub fn entry_hashed<'a> (&'a mut self, hash: u64, key: &'a [u8]) -> Entry<'a, V, S> {
+        let hash = hash as usize;
+        match self.find(hash, key) {
+            Some((index, position)) => Entry::Occupied(OccupiedEntry { map: self, index, position }),
+            None => Entry::Vacant(VacantEntry { map: self, key, hash })
+        }
+    }
+
+    /// the number of entries
+    pub fn len (&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty (&self) -> bool {
+        self.count == 0
+    }
+
+    /// every key and value, in no particular order
+    pub fn iter (&self) -> Iter<'_, V> {
+        Iter {
+            buckets: self.table().iter(),
+            entries: [].iter(),
+            remaining: self.count
+        }
+    }
+
+    /// every key with a mutable value, in no particular order
+    pub fn iter_mut (&mut self) -> IterMut<'_, V> {
+        let remaining = self.count;
+        IterMut {
+            buckets: self.table_mut().iter_mut(),
+            entries: [].iter_mut(),
+            remaining
+        }
+    }
+
+    /// every key, in no particular order
+    pub fn keys (&self) -> Keys<'_, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// keeps only the entries {keep} returns true for
+    pub fn retain <F> (&mut self, mut keep: F) where F: FnMut(&[u8], &mut V) -> bool {
+        let mut removed = 0;
+        for list in self.table_mut().iter_mut() {
+            let before = list.len();
+            list.retain_mut(|e| keep(&e.key, &mut e.val));
+            removed += before - list.len();
+        }
+        self.count -= removed;
+        self.shrink();
+    }
+
+    /// takes every entry out of the map, leaving it empty with the smallest table
+    pub fn drain (&mut self) -> Drain<V> {
+        let remaining = self.count;
+        let buckets = self.take_table(STATIC_SZ);
+        self.count = 0;
+        Drain {
+            buckets: buckets.into_iter(),
+            entries: Vec::new().into_iter(),
+            remaining
+        }
+    }
+
+    /// the number of buckets
+    pub fn capacity (&self) -> usize {
+        self.capacity
+    }
+
+    /// the bucket and the position in it of {key}, whose hash is {hash}
+    fn find (&self, hash: usize, key: &[u8]) -> Option<(usize, usize)> {
+        let index = hash & (self.capacity - 1);
+        self.table()[index].iter().position(|e| e.hash == hash && &e.key[..] == key).map(|position| (index, position))
+    }
+
+    fn remove_at (&mut self, index: usize, position: usize) -> V {
+        let entry = self.table_mut()[index].swap_remove(position);
+        self.count -= 1;
+        self.shrink();
+        entry.val
+    }
+
+    fn table (&self) -> &[Vec<HashEntry<V>>] {
+        match self.table {
+            InlineVec::Static(ref arr) => &arr[..],
+            InlineVec::Dynamic(ref vec) => &vec[..]
+        }
+    }
+
+    fn table_mut (&mut self) -> &mut [Vec<HashEntry<V>>] {
+        match self.table {
+            InlineVec::Static(ref mut arr) => &mut arr[..],
+            InlineVec::Dynamic(ref mut vec) => &mut vec[..]
+        }
+    }
+
+    /// doubles the table if it is over the load factor
+    fn grow (&mut self) {
+        if self.count * MAX_LOAD_DEN > self.capacity * MAX_LOAD_NUM {
+            let capacity = self.capacity * 2;
+            self.resize(capacity);
+        }
+    }
+
+    /// halves the table until it is no longer mostly empty, never below STATIC_SZ buckets
+    fn shrink (&mut self) {
+        let mut capacity = self.capacity;
+        while capacity > STATIC_SZ && self.count * MIN_LOAD_DEN < capacity {
+            capacity /= 2;
+        }
+        if capacity != self.capacity {
+            self.resize(capacity);
+        }
+    }
+
+    /// moves every entry into a heap table of {capacity} buckets. entries keep the hash they were
+    /// inserted with, so no key is hashed again
+    fn resize (&mut self, capacity: usize) {
+        for bucket in self.take_table(capacity) {
+            for entry in bucket {
+                self.table_mut()[entry.hash & (capacity - 1)].push(entry);
+            }
+        }
+    }
+
+    /// swaps the table for an empty heap one of {capacity} buckets, returning the old buckets
+    fn take_table (&mut self, capacity: usize) -> Vec<Vec<HashEntry<V>>> {
+        let table = (0..capacity).map(|_| Vec::new()).collect();
+        self.capacity = capacity;
+        match mem::replace(&mut self.table, InlineVec::Dynamic(table)) {
+            InlineVec::Static(arr) => Vec::from(arr),
+            InlineVec::Dynamic(vec) => vec
+        }
+    }
+
+    /// the hasher keys are hashed with
+    pub fn hasher (&self) -> &S {
+        &self.hasher
+    }
+
+    fn make_hash (&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize
+    }
+
+}
+
+impl<'a, V, S: BuildHasher> IntoIterator for &'a SliceMap <V, S> {
+    type Item = (&'a [u8], &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter (self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<'a, V, S: BuildHasher> IntoIterator for &'a mut SliceMap <V, S> {
+    type Item = (&'a [u8], &'a mut V);
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter (self) -> IterMut<'a, V> {
+        self.iter_mut()
+    }
+}
+
+/// see SliceMap::iter
+pub struct Iter<'a, V: 'a> {
+    buckets: slice::Iter<'a, Vec<HashEntry<V>>>,
+    entries: slice::Iter<'a, HashEntry<V>>,
+    remaining: usize
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a [u8], &'a V);
+
+    fn next (&mut self) -> Option<(&'a [u8], &'a V)> {
+        loop {
+            if let Some(e) = self.entries.next() {
+                self.remaining -= 1;
+                return Some((&e.key, &e.val));
+            }
+            // empty buckets are skipped without touching the count, so stop once it is reached
+            if self.remaining == 0 {
+                return None;
+            }
+            self.entries = self.buckets.next()?.iter();
+        }
+    }
+
+    fn size_hint (&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Iter<'a, V> {}
+
+/// see SliceMap::iter_mut
+pub struct IterMut<'a, V: 'a> {
+    buckets: slice::IterMut<'a, Vec<HashEntry<V>>>,
+    entries: slice::IterMut<'a, HashEntry<V>>,
+    remaining: usize
+}
+
+impl<'a, V> Iterator for IterMut<'a, V> {
+    type Item = (&'a [u8], &'a mut V);
+
+    fn next (&mut self) -> Option<(&'a [u8], &'a mut V)> {
+        loop {
+            if let Some(e) = self.entries.next() {
+                self.remaining -= 1;
+                return Some((&e.key, &mut e.val));
+            }
+            if self.remaining == 0 {
+                return None;
+            }
+            self.entries = self.buckets.next()?.iter_mut();
+        }
+    }
+
+    fn size_hint (&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, V> ExactSizeIterator for IterMut<'a, V> {}
+
+/// see SliceMap::keys
+pub struct Keys<'a, V: 'a> {
+    inner: Iter<'a, V>
+}
+
+impl<'a, V> Iterator for Keys<'a, V> {
+    type Item = &'a [u8];
+
+    fn next (&mut self) -> Option<&'a [u8]> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn size_hint (&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Keys<'a, V> {}
+
+/// see SliceMap::drain. the map is already empty, dropping this drops the rest of the entries
+pub struct Drain<V> {
+    buckets: vec::IntoIter<Vec<HashEntry<V>>>,
+    entries: vec::IntoIter<HashEntry<V>>,
+    remaining: usize
+}
+
+impl<V> Iterator for Drain<V> {
+    type Item = (Vec<u8>, V);
+
+    fn next (&mut self) -> Option<(Vec<u8>, V)> {
+        loop {
+            if let Some(e) = self.entries.next() {
+                self.remaining -= 1;
+                return Some((e.key, e.val));
+            }
+            if self.remaining == 0 {
+                return None;
+            }
+            self.entries = self.buckets.next()?.into_iter();
+        }
+    }
+
+    fn size_hint (&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<V> ExactSizeIterator for Drain<V> {}
+
+/// a place in a SliceMap that may or may not hold a value, see SliceMap::entry
+pub enum Entry<'a, V: 'a, S: 'a> {
+    Occupied(OccupiedEntry<'a, V, S>),
+    Vacant(VacantEntry<'a, V, S>)
+}
+
+impl<'a, V, S: BuildHasher> Entry<'a, V, S> {
+    pub fn key (&self) -> &[u8] {
+        match *self {
+            Entry::Occupied(ref e) => e.key(),
+            Entry::Vacant(ref e) => e.key()
+        }
+    }
+
+    /// the value, after inserting {default} if there was none
+    pub fn or_insert (self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// the value, after inserting the result of {default} if there was none
+    pub fn or_insert_with <F> (self, default: F) -> &'a mut V where F: FnOnce() -> V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default())
+        }
+    }
+
+    /// runs {f} on the value if there is one
+    pub fn and_modify <F> (mut self, f: F) -> Self where F: FnOnce(&mut V) {
+        if let Entry::Occupied(ref mut e) = self {
+            f(e.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, V: Default, S: BuildHasher> Entry<'a, V, S> {
+    pub fn or_default (self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+pub struct OccupiedEntry<'a, V: 'a, S: 'a> {
+    map: &'a mut SliceMap<V, S>,
+
+    /// where the entry is in the table
+    index: usize,
+    position: usize
+}
+
+impl<'a, V, S: BuildHasher> OccupiedEntry<'a, V, S> {
+    pub fn key (&self) -> &[u8] {
+        &self.map.table()[self.index][self.position].key
+    }
+
+    pub fn get (&self) -> &V {
+        &self.map.table()[self.index][self.position].val
+    }
+
+    pub fn get_mut (&mut self) -> &mut V {
+        &mut self.map.table_mut()[self.index][self.position].val
+    }
+
+    /// the value, borrowed for as long as the map was
+    pub fn into_mut (self) -> &'a mut V {
+        &mut self.map.table_mut()[self.index][self.position].val
+    }
+
+    /// replaces the value, returning the old one
+    pub fn insert (&mut self, val: V) -> V {
+        mem::replace(self.get_mut(), val)
+    }
+
+    /// takes the entry out of the map
+    pub fn remove (self) -> V {
+        self.map.remove_at(self.index, self.position)
+    }
+}
+
+pub struct VacantEntry<'a, V: 'a, S: 'a> {
+    map: &'a mut SliceMap<V, S>,
+    key: &'a [u8],
+    hash: usize
+}
+
+impl<'a, V, S: BuildHasher> VacantEntry<'a, V, S> {
+    pub fn key (&self) -> &[u8] {
+        self.key
+    }
+
+    /// fills the entry in with {val}, copying the key
+    pub fn insert (self, val: V) -> &'a mut V {
+        let (hash, key, map) = (self.hash, self.key, self.map);
+        let index = hash & (map.capacity - 1);
+        map.table_mut()[index].push(HashEntry {
+            key: key.to_owned(),
+            val,
+            hash
+        });
+        map.count += 1;
+        map.grow();
+        // growing may have moved it
+        let (index, position) = map.find(hash, key).expect("just inserted");
+        &mut map.table_mut()[index][position].val
+    }
 }
 
 #[derive(Clone)]
 pub struct HashEntry <V> {
     key: Vec<u8>,
     val: V,
+
+    /// the full hash of the key, kept so that lookups can skip most key comparisons and resizing
+    /// doesn't rehash
     hash: usize
 }
+
+#[cfg(test)]
+mod test {
+    use super::{SliceMap, Hashing, Entry, STATIC_SZ};
+
+    fn key (i: usize) -> Vec<u8> {
+        format!("topic.{}", i).into_bytes()
+    }
+
+    #[test]
+    fn grows_past_the_static_table() {
+        let mut map = SliceMap::new();
+        for i in 0..10 * STATIC_SZ {
+            map.insert(&key(i), i);
+        }
+        assert!(map.capacity() >= 10 * STATIC_SZ * 4 / 3, "{} buckets", map.capacity());
+        assert!(map.capacity().is_power_of_two());
+        for i in 0..10 * STATIC_SZ {
+            assert_eq!(map.get(&key(i)), Some(&i));
+        }
+        assert_eq!(map.get(b"topic.none"), None);
+    }
+
+    #[test]
+    fn every_hashing_spreads_keys_over_the_buckets() {
+        for hashing in &[Hashing::Fast, Hashing::keyed()] {
+            let mut map = SliceMap::with_hasher(hashing.clone());
+            for i in 0..STATIC_SZ / 2 {
+                map.insert(&key(i), i);
+            }
+            let longest = map.table().iter().map(|bucket| bucket.len()).max().unwrap();
+            assert!(longest <= 8, "{} hashing chained {} keys", hashing, longest);
+            assert!((0..STATIC_SZ / 2).all(|i| map.get(&key(i)) == Some(&i)));
+        }
+        // trailing zeroes still make a different key
+        let map = SliceMap::<()>::new();
+        assert_ne!(map.make_hash(b"a"), map.make_hash(b"a\0"));
+    }
+
+    #[test]
+    fn iterates_over_every_entry() {
+        let mut map = SliceMap::new();
+        assert!(map.is_empty() && map.iter().next().is_none());
+        for i in 0..3 * STATIC_SZ {
+            map.insert(&key(i), i);
+        }
+        map.insert(&key(0), 0);
+        assert_eq!(map.len(), 3 * STATIC_SZ);
+        assert_eq!(map.iter().len(), 3 * STATIC_SZ);
+
+        for (_, v) in map.iter_mut() {
+            *v *= 2;
+        }
+        let mut seen = map.iter().map(|(k, v)| (k.to_vec(), *v)).collect::<Vec<_>>();
+        seen.sort_by_key(|&(_, v)| v);
+        assert_eq!(seen, (0..3 * STATIC_SZ).map(|i| (key(i), 2 * i)).collect::<Vec<_>>());
+        assert_eq!(map.keys().filter(|k| k.ends_with(b"7")).count(), (0..3 * STATIC_SZ).filter(|i| i % 10 == 7).count());
+    }
+
+    #[test]
+    fn removes_return_the_value() {
+        let mut map = SliceMap::new();
+        map.insert(b"a", 1);
+        assert_eq!(map.remove(b"b"), None);
+        assert_eq!(map.remove(b"a"), Some(1));
+        assert_eq!(map.remove(b"a"), None);
+        assert!(map.is_empty());
+
+        for i in 0..4 * STATIC_SZ {
+            map.insert(&key(i), i);
+        }
+        map.retain(|_, v| *v % 100 == 0);
+        assert_eq!(map.len(), 4 * STATIC_SZ / 100 + 1);
+        assert_eq!(map.capacity(), STATIC_SZ);
+        assert!(map.iter().all(|(k, v)| *v % 100 == 0 && map.get(k) == Some(v)));
+
+        let mut drained = map.drain().map(|(_, v)| v).collect::<Vec<_>>();
+        drained.sort();
+        assert_eq!(drained, (0..4 * STATIC_SZ).step_by(100).collect::<Vec<_>>());
+        assert!(map.is_empty() && map.get(&key(0)).is_none());
+    }
+
+    #[test]
+    fn entries_look_up_once() {
+        let mut map = SliceMap::<Vec<u32>>::new();
+        map.entry(b"a").or_default().push(1);
+        map.entry(b"a").or_default().push(2);
+        map.entry(b"b").and_modify(|v| v.push(3)).or_insert_with(|| vec![4]);
+        assert_eq!(map.get(b"a"), Some(&vec![1, 2]));
+        assert_eq!(map.get(b"b"), Some(&vec![4]));
+
+        match map.entry(b"a") {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.key(), b"a");
+                assert_eq!(e.insert(vec![5]), vec![1, 2]);
+                assert_eq!(e.remove(), vec![5]);
+            }
+            Entry::Vacant(_) => panic!("a is in the map")
+        }
+        assert_eq!(map.len(), 1);
+        assert!(!map.contains_key(b"a"));
+
+        // inserting through a vacant entry may grow the table under it
+        for i in 0..4 * STATIC_SZ {
+            *map.entry(&key(i)).or_insert(vec![]) = vec![i as u32];
+        }
+        assert!((0..4 * STATIC_SZ).all(|i| map.get(&key(i)) == Some(&vec![i as u32])));
+    }
+
+    #[test]
+    fn modify_or_else_grows_too() {
+        let mut map = SliceMap::new();
+        for i in 0..4 * STATIC_SZ {
+            map.modify_or_else(&key(i), |v| *v += 1, || 0);
+            map.modify_or_else(&key(i), |v| *v += 1, || 0);
+        }
+        assert!(map.capacity() > STATIC_SZ);
+        assert!((0..4 * STATIC_SZ).all(|i| map.get(&key(i)) == Some(&1)));
+    }
+
+    #[test]
+    fn shrinks_back_when_emptied() {
+        let mut map = SliceMap::new();
+        for i in 0..16 * STATIC_SZ {
+            map.insert(&key(i), i);
+        }
+        let grown = map.capacity();
+        for i in 0..16 * STATIC_SZ - 10 {
+            assert!(map.delete(&key(i)));
+        }
+        assert_eq!(map.capacity(), STATIC_SZ);
+        assert!(grown > map.capacity());
+        for i in 16 * STATIC_SZ - 10..16 * STATIC_SZ {
+            assert_eq!(map.get(&key(i)), Some(&i));
+        }
+        assert!(!map.delete(&key(0)));
+    }
+}
@@ -1,116 +1,454 @@
-use std::sync::mpsc::{Sender, Receiver, channel};
-use std::thread;
-use protocol::{get_message, RawMessage};
-use slice_map::SliceMap;
-use std::collections::{HashSet, HashMap};
-use std::net::{SocketAddr, TcpStream};
-use mio::tcp::TcpStream as MioTcpStream;
-use rpc::parse;
-
-/// an interface for a stateful worker capable of acting in a threadpool
-pub trait PoolWorker <T, R> {
-    fn new (Vec<Sender<T>>) -> Self;
+use std::sync::mpsc::{Sender, SyncSender, Receiver, SendError, TrySendError, channel, sync_channel};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-    /// does some arbitrary unit of work
-    fn func(&mut self, &T) -> R;
-}
+/// how much work may be queued on each worker by default
+pub const DEFAULT_CAPACITY: usize = 1024;
 
-/// backs a concrete implementation of a PoolWorker
-pub struct QueuePoolWorker {
+/// something for the workers to call, set once they are running
+type Hook = Arc<Mutex<Option<Box<dyn Fn() + Send>>>>;
 
-    /// locally cached mapping of topics (a bunch of bytes) to a collection of subcriber info (tcp
-    /// sockets) 
-    topic_map: SliceMap<HashMap<SocketAddr, TcpStream>>,
+fn call(hook: &Hook) {
+    if let Some(ref f) = *hook.lock().unwrap_or_else(|e| e.into_inner()) {
+        f();
+    }
+}
 
-    /// locally cached mapping of socket addresses to a collection of topics 
-    interest_map: HashMap<SocketAddr, HashSet<Vec<u8>>>,
+/// what travels over a worker's queue. stopping is the pool's business, so that it doesn't need
+/// to know anything about the work
+enum Job<T> {
+    Work(T),
+    Stop
+}
 
-    /// channels to other threads to broadcast messages on (relatively low priority i.e. does not
-    /// interrupt)
-    contacts: Vec<Sender<RawMessage>>,
+/// the sending end of a worker's queue, counting the work that is waiting on it
+pub struct Mailbox<T> {
+    sender: SyncSender<Job<T>>,
+    depth: Arc<AtomicUsize>
 }
 
-impl PoolWorker<RawMessage, ()> for QueuePoolWorker {
-    fn new (contacts: Vec<Sender<RawMessage>>) -> QueuePoolWorker {
-        QueuePoolWorker {
-            topic_map: SliceMap::new(),
-            contacts: contacts,
-            interest_map: HashMap::new()
+impl<T> Clone for Mailbox<T> {
+    fn clone(&self) -> Mailbox<T> {
+        Mailbox {
+            sender: self.sender.clone(),
+            depth: self.depth.clone()
         }
     }
+}
+
+impl<T> Mailbox<T> {
+    /// queues {task}, waiting for room if the queue is full
+    pub fn send(&self, task: T) -> Result<(), SendError<T>> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(Job::Work(task)).map_err(|SendError(job)| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            match job {
+                Job::Work(task) => SendError(task),
+                Job::Stop => unreachable!("work was sent")
+            }
+        })
+    }
 
-    /// does something with a message
-    fn func (&mut self, message: &RawMessage) {
-        parse(&message, &self.contacts, &mut self.topic_map, &mut self.interest_map);
+    /// queues {task} unless the queue is full
+    pub fn try_send(&self, task: T) -> Result<(), TrySendError<T>> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.try_send(Job::Work(task)).map_err(|e| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            match e {
+                TrySendError::Full(Job::Work(task)) => TrySendError::Full(task),
+                TrySendError::Disconnected(Job::Work(task)) => TrySendError::Disconnected(task),
+                _ => unreachable!("work was sent")
+            }
+        })
+    }
+
+    /// asks the worker to stop once it is done with the work queued before this, false if the
+    /// queue is full
+    fn stop(&self) -> bool {
+        !matches!(self.sender.try_send(Job::Stop), Err(TrySendError::Full(_)))
+    }
+
+    /// work sent that the worker has not picked up yet
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// an interface for a stateful worker capable of acting in a threadpool, a pool makes its workers
+/// with the closure it is given, see StatePool::with_capacity
+pub trait PoolWorker <T, R> {
+    /// does some arbitrary unit of work
+    fn func(&mut self, _: &T) -> R;
+
+    /// what to report for the unit of work a worker died on, if anything. the worker is gone by
+    /// then, so this only has the work to go on
+    fn lost(_: &T) -> Option<R> where Self: Sized {
+        None
     }
 }
 
 pub struct StatePool <T, R> {
     /// handles of channels to workers, you can send work to them from here
-    pub workers: Vec<Sender<T>>,
+    pub workers: Vec<Mailbox<T>>,
 
-    /// used to receive messages from worker threads
+    /// what the workers made of each value, in the order each one finished them. nothing limits
+    /// how many pile up, so they should be read, see when_done
     pub wait_rx: Receiver<R>,
 
     /// the last worker that we sent work to, used for certain strats e.g. round robin
-    curr_index: usize
+    curr_index: usize,
+
+    /// join handles of the worker threads, indexed the same as {workers}
+    threads: Vec<Option<JoinHandle<()>>>,
+
+    /// each worker thread sends its index here as it exits, even if it panicked
+    exited_rx: Receiver<usize>,
+
+    /// starts the thread for the worker at an index, again if it died
+    spawn: Box<dyn Fn(usize) -> JoinHandle<()> + Send>,
+
+    /// the number of workers that died and were started again
+    restarts: Arc<AtomicUsize>,
+
+    /// set by shutdown, after which workers are left to exit
+    stopping: bool,
+
+    /// see when_freed
+    freed: Hook,
+
+    /// see when_done
+    done: Hook
 }
 
-//fyi lose generics here because unable to return traits in impl generics right now
+/// reports a worker thread as exited when dropped, so that panics are reported too, along with
+/// the work it died on
+struct ExitGuard<T, R> {
+    index: usize,
+    exited: Sender<usize>,
+
+    /// the work being done, if any
+    task: Option<T>,
+    done: Sender<R>,
+    lost: fn(&T) -> Option<R>,
+
+    /// see StatePool::when_done
+    finished: Hook
+}
+
+impl<T, R> ExitGuard<T, R> {
+    /// holds on to {task} while it is worked on
+    fn hold(&mut self, task: T) -> &T {
+        self.task.insert(task)
+    }
+}
+
+impl<T, R> Drop for ExitGuard<T, R> {
+    fn drop(&mut self) {
+        if let Some(result) = self.task.take().and_then(|task| (self.lost)(&task)) {
+            let _ = self.done.send(result);
+        }
+        let _ = self.exited.send(self.index);
+        // whoever reads the results is the one to restart the worker
+        call(&self.finished);
+    }
+}
 
-impl StatePool <RawMessage, ()> {
+impl<T: Send + 'static, R: Send + 'static> StatePool <T, R> {
 
-    pub fn handle_messages (&mut self, socket: &mut MioTcpStream) {
-        loop {
-            match get_message(socket) {
-                //defers work to the pool
-                Some(s) => { self.send_rr(s); },
-                None => return
+    /// sends a value to the pool round robin, after restarting any worker that died. a full
+    /// queue is skipped, the value is handed back if they all are
+    pub fn send_rr(&mut self, task: T) -> Result<(), T> {
+        self.supervise();
+        let mut task = task;
+        for _ in 0..self.workers.len() {
+            let worker = &self.workers[self.curr_index];
+            self.curr_index = (self.curr_index + 1) % self.workers.len();
+            task = match worker.try_send(task) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(task)) => task,
+                Err(TrySendError::Disconnected(_)) => unreachable!("the pool holds every worker's queue")
             };
         }
+        Err(task)
     }
 
-    /// sends a value to the pool round robin
-    pub fn send_rr(&mut self, task: RawMessage) {
-        let worker = &self.workers[self.curr_index];
-        self.curr_index = (self.curr_index + 1) % self.workers.len();
-        worker.send(task).unwrap();
+    /// starts {num_threads} workers with queues of DEFAULT_CAPACITY, see with_capacity
+    pub fn new <P, W> (num_threads: usize, new_worker: W) -> StatePool<T, R>
+    where P: PoolWorker<T, R> + Send + 'static, W: Fn(Vec<Mailbox<T>>) -> P + Send + 'static {
+        StatePool::with_capacity(num_threads, DEFAULT_CAPACITY, new_worker)
     }
 
-    pub fn new <W> (num_threads: usize, new_worker: W) -> StatePool<RawMessage, ()> where W: Fn(Vec<Sender<RawMessage>>) -> QueuePoolWorker {
+    /// starts {num_threads} workers, each made by {new_worker} from the mailboxes of the others
+    /// and queueing up to {capacity} values. what they return is sent to {wait_rx}. a worker that
+    /// dies is made again by {new_worker}, so any state it needs to survive that has to be shared
+    /// through it
+    pub fn with_capacity <P, W> (num_threads: usize, capacity: usize, new_worker: W) -> StatePool<T, R>
+    where P: PoolWorker<T, R> + Send + 'static, W: Fn(Vec<Mailbox<T>>) -> P + Send + 'static {
 
         let (done, wait) = channel();
+        let (exited_tx, exited_rx) = channel();
+        let freed: Hook = Arc::new(Mutex::new(None));
+        let finished: Hook = Arc::new(Mutex::new(None));
         let _workers = (0..num_threads).map(|_| {
-            channel()
+            sync_channel(capacity)
+        }).collect::<Vec<_>>();
+
+        let contacts = _workers.iter().map(|(tx, _)| Mailbox {
+            sender: tx.clone(),
+            depth: Arc::new(AtomicUsize::new(0))
         }).collect::<Vec<_>>();
 
-        let contacts = _workers.iter().map(|&(ref tx, _)| tx.clone()).collect::<Vec<_>>();
+        // the pool keeps the receiving ends, so queues outlive the threads draining them and a
+        // restarted worker picks up where the dead one left off
+        let queues = _workers.into_iter().map(|(_, rx)| Arc::new(Mutex::new(rx))).collect::<Vec<_>>();
 
-        for (i, (_, _work)) in _workers.into_iter().enumerate() {
+        let others = contacts.clone();
+        let (on_freed, on_done) = (freed.clone(), finished.clone());
+        let spawn = move |i: usize| {
             let _done = done.clone();
+            let (freed, finished) = (on_freed.clone(), on_done.clone());
+            let guard = ExitGuard {
+                index: i,
+                exited: exited_tx.clone(),
+                task: None,
+                done: done.clone(),
+                lost: P::lost,
+                finished: on_done.clone()
+            };
+            let depth = others[i].depth.clone();
+            let queue = queues[i].clone();
 
             //exclude own sender from contact info
-            let mut other_contacts = contacts.clone();
+            let mut other_contacts = others.clone();
             other_contacts.remove(i);
             let mut worker = new_worker(other_contacts);
 
-            let _ = thread::spawn(move || {
-                loop {
-                    let _ = match _work.recv() {
-                        Ok(task) => {
-                            let res = worker.func(&task);
-                            let _ = _done.send(res);
-                        }
-                        _ => ()
-                    };
+            thread::spawn(move || {
+                let mut guard = guard;
+                // only ever locked by this worker, a panic poisons it for nothing
+                let _work = queue.lock().unwrap_or_else(|e| e.into_inner());
+                // exits when stopped, or once every sender to this worker is gone
+                while let Ok(Job::Work(task)) = _work.recv() {
+                    // counts senders that just found it full too, so no wake up is missed
+                    if depth.fetch_sub(1, Ordering::Relaxed) >= capacity {
+                        call(&freed);
+                    }
+                    let result = worker.func(guard.hold(task));
+                    guard.task = None;
+                    let _ = _done.send(result);
+                    call(&finished);
                 }
-            });
-        }
+            })
+        };
 
         StatePool {
             workers: contacts,
             wait_rx: wait,
-            curr_index: 0
+            curr_index: 0,
+            threads: (0..num_threads).map(|i| Some(spawn(i))).collect(),
+            exited_rx,
+            spawn: Box::new(spawn),
+            restarts: Arc::new(AtomicUsize::new(0)),
+            stopping: false,
+            freed,
+            done: finished
+        }
+    }
+
+    /// has the workers call {f} when they take work from a full queue, so that whoever was
+    /// turned away by send_rr knows to try again. it runs on the worker, so it should be quick
+    pub fn when_freed<F: Fn() + Send + 'static>(&self, f: F) {
+        *self.freed.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(f));
+    }
+
+    /// has the workers call {f} after every result they send to {wait_rx}, so that it can be read
+    /// without polling, and as they die, so that they can be restarted without waiting for the
+    /// next send_rr. it runs on the worker, so it should be quick
+    pub fn when_done<F: Fn() + Send + 'static>(&self, f: F) {
+        *self.done.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(f));
+    }
+
+    /// starts again every worker that has died since the last call, returning how many there
+    /// were. the work each one was doing is reported as PoolWorker::lost says, the rest of its
+    /// queue is kept. once the pool is shutting down workers that exit are only joined
+    pub fn supervise(&mut self) -> usize {
+        let mut restarted = 0;
+        while let Ok(i) = self.exited_rx.try_recv() {
+            let panicked = match self.threads[i].take() {
+                Some(handle) => handle.join().is_err(),
+                None => continue
+            };
+            if self.stopping {
+                continue;
+            }
+            error!(worker = i, panicked = panicked; "worker died, restarting it");
+            self.threads[i] = Some((self.spawn)(i));
+            restarted += 1;
+        }
+        self.restarts.fetch_add(restarted, Ordering::Relaxed);
+        restarted
+    }
+
+    /// the number of workers restarted so far, for reporting
+    pub fn restarts(&self) -> Arc<AtomicUsize> {
+        self.restarts.clone()
+    }
+
+    /// the depth counter of every worker's queue, for reporting
+    pub fn queue_depths(&self) -> Vec<Arc<AtomicUsize>> {
+        self.workers.iter().map(|w| w.depth.clone()).collect()
+    }
+
+    /// stops every worker once it has finished the work queued ahead of the stop request, waiting
+    /// up to {timeout} for them. returns the number of workers that were joined, the rest are
+    /// abandoned
+    pub fn shutdown (&mut self, timeout: Duration) -> usize {
+        // a dead worker's queue would never be drained
+        self.supervise();
+        self.stopping = true;
+        let deadline = Instant::now() + timeout;
+        for sender in self.workers.iter() {
+            // a full queue has to make room for the request first
+            while !sender.stop() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        let mut joined = 0;
+        while self.threads.iter().any(|t| t.is_some()) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.exited_rx.recv_timeout(remaining) {
+                Ok(i) => {
+                    if let Some(handle) = self.threads[i].take() {
+                        let _ = handle.join();
+                        joined += 1;
+                    }
+                }
+                Err(_) => break
+            }
+        }
+        joined
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StatePool, PoolWorker, Mailbox};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{Receiver, channel};
+
+    /// keeps a running total, and passes every number on to the next worker once
+    struct Adder {
+        total: u64,
+        next: Vec<Mailbox<(u64, bool)>>
+    }
+
+    impl Adder {
+        fn new(next: Vec<Mailbox<(u64, bool)>>) -> Adder {
+            Adder { total: 0, next }
+        }
+    }
+
+    impl PoolWorker<(u64, bool), u64> for Adder {
+        fn func(&mut self, &(n, forwarded): &(u64, bool)) -> u64 {
+            self.total += n;
+            if !forwarded {
+                let _ = self.next[0].send((n, true));
+            }
+            self.total
+        }
+    }
+
+    /// panics on zero
+    struct Divider;
+
+    impl PoolWorker<u64, u64> for Divider {
+        fn func(&mut self, &n: &u64) -> u64 {
+            100 / n
+        }
+
+        fn lost(_: &u64) -> Option<u64> {
+            Some(u64::MAX)
+        }
+    }
+
+    #[test]
+    fn dead_workers_are_restarted() {
+        let mut pool = StatePool::new(1, |_| Divider);
+        let (died_tx, died) = channel();
+        pool.when_done(move || { let _ = died_tx.send(()); });
+        pool.workers[0].send(0).unwrap();
+        pool.workers[0].send(4).unwrap();
+
+        // the worker dies on the first and says so, the second waits in its queue for the next one
+        assert_eq!(pool.wait_rx.recv_timeout(Duration::from_secs(5)), Ok(u64::MAX));
+        assert_eq!(died.recv_timeout(Duration::from_secs(5)), Ok(()));
+        assert_eq!(pool.supervise(), 1);
+        pool.send_rr(5).unwrap();
+        assert_eq!(pool.restarts().load(Ordering::Relaxed), 1);
+        assert_eq!(pool.wait_rx.recv_timeout(Duration::from_secs(5)), Ok(25));
+        assert_eq!(pool.wait_rx.recv_timeout(Duration::from_secs(5)), Ok(20));
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 1);
+    }
+
+    /// waits for the gate to open on every value
+    struct Gated(Arc<Mutex<Receiver<()>>>);
+
+    impl PoolWorker<u64, ()> for Gated {
+        fn func(&mut self, _: &u64) {
+            self.0.lock().unwrap().recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn full_queues_turn_work_away() {
+        let (open, gate) = channel();
+        let gate = Arc::new(Mutex::new(gate));
+        let mut pool = StatePool::with_capacity(1, 1, move |_| Gated(gate.clone()));
+        let (freed_tx, freed) = channel();
+        pool.when_freed(move || freed_tx.send(()).unwrap());
+
+        // one value being worked on, one queued
+        pool.send_rr(1).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.workers[0].depth() > 0 {
+            assert!(Instant::now() < deadline, "the worker never picked up its work");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // taking it made room in a full queue
+        assert_eq!(freed.recv_timeout(Duration::from_secs(5)), Ok(()));
+        pool.send_rr(2).unwrap();
+        assert_eq!(pool.send_rr(3), Err(3));
+        assert!(freed.try_recv().is_err());
+
+        open.send(()).unwrap();
+        assert_eq!(freed.recv_timeout(Duration::from_secs(5)), Ok(()));
+        pool.send_rr(3).unwrap();
+
+        open.send(()).unwrap();
+        open.send(()).unwrap();
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 1);
+    }
+
+    #[test]
+    fn pools_run_any_worker() {
+        let mut pool = StatePool::new(2, Adder::new);
+        let done = Arc::new(AtomicUsize::new(0));
+        let counter = done.clone();
+        pool.when_done(move || { counter.fetch_add(1, Ordering::Relaxed); });
+        for n in 1..=10 {
+            pool.send_rr((n, false)).unwrap();
         }
+        // every number is added by both workers
+        let results = (0..20).map(|_| pool.wait_rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+        assert_eq!(results.iter().max(), Some(&55));
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 2);
+        // once for every result, and once more for every worker as it exits
+        assert_eq!(done.load(Ordering::Relaxed), 22);
     }
 }
@@ -0,0 +1,174 @@
+use std::{fs, io};
+use std::io::{BufReader, Read, Write};
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rustls::{self, Certificate, PrivateKey, RootCertStore, ServerConnection};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls_pemfile::{self, Item};
+use transport::Stream;
+
+/// certificate settings for a TLS listener. paths are to PEM files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// the server's certificate chain, leaf first
+    pub cert: PathBuf,
+    pub key: PathBuf,
+
+    /// when set, clients must present a certificate signed by one of these CAs
+    pub client_ca: Option<PathBuf>
+}
+
+impl TlsConfig {
+    pub fn new<P: Into<PathBuf>>(cert: P, key: P) -> TlsConfig {
+        TlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None
+        }
+    }
+
+    pub fn client_ca<P: Into<PathBuf>>(mut self, ca: P) -> TlsConfig {
+        self.client_ca = Some(ca.into());
+        self
+    }
+
+    /// reads the certificates and key, done once when the listener is bound
+    pub fn server_config(&self) -> io::Result<Arc<rustls::ServerConfig>> {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_ca {
+            Some(ref ca) => {
+                let verifier = AllowAnyAuthenticatedClient::new(load_roots(ca)?);
+                builder.with_client_cert_verifier(verifier.boxed())
+            }
+            None => builder.with_no_client_auth()
+        };
+        let config = builder.with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// a client configuration trusting the CAs in {ca}, optionally presenting a client certificate
+/// given as (cert, key) paths
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        None => builder.with_no_client_auth()
+    };
+    Ok(Arc::new(config))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<fs::File>> {
+    fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no certificates found", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(roots)
+}
+
+/// the first private key in the file, in any of the formats rustls understands
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = open(path)?;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => ()
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no private key found", path.display())))
+}
+
+/// the server side of a TLS session over a non blocking socket. reads and writes behave like the
+/// socket's: WouldBlock means try again once the socket is ready
+pub struct TlsStream {
+    conn: ServerConnection,
+    sock: Stream
+}
+
+impl TlsStream {
+    pub fn new(config: Arc<rustls::ServerConfig>, sock: Stream) -> io::Result<TlsStream> {
+        let conn = ServerConnection::new(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsStream { conn, sock })
+    }
+
+    /// whether there is TLS data the socket has not taken yet
+    pub fn is_holding(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    /// writes out as much pending TLS data as the socket takes
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                // Ok(0) is a clean close_notify
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e)
+            }
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                // lets the peer know why, if it can
+                let _ = self.write_pending();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            // handshake messages are small, so they are not expected to block
+            match self.write_pending() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                other => other?
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    /// plaintext is buffered by the session, so this only blocks while earlier data is pending
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        let written = self.conn.writer().write(buf)?;
+        match self.write_pending() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(written),
+            Err(e) => Err(e),
+            Ok(()) => Ok(written)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()
+    }
+}
+
+impl AsRawFd for TlsStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
@@ -0,0 +1,221 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::hash::BuildHasher;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use slice_map::{SliceMap, Entry, Hashing};
+use transport::Peer;
+
+/// shards in the server's index. each starts out with a static SliceMap table, so this is a
+/// trade between memory and how often workers and the event loop meet on the same lock
+pub const DEFAULT_SHARDS: usize = 32;
+
+/// something that can be subscribed to a topic
+pub trait Subscriber: Clone {
+    /// tells subscribers apart, the same subscriber is only ever listed once per topic
+    fn id(&self) -> usize;
+}
+
+impl Subscriber for Arc<Peer> {
+    fn id(&self) -> usize {
+        Peer::id(self)
+    }
+}
+
+/// bare ids, for when there is nothing to deliver to
+impl Subscriber for usize {
+    fn id(&self) -> usize {
+        *self
+    }
+}
+
+/// the subscribers of one topic, in the order of their ids. a list that a worker got hold of is
+/// never changed, a change to it copies it first, so a worker can deliver from the list it got
+/// without holding any lock. a list nobody holds on to is changed in place
+pub type Subscribers<T> = Arc<Vec<T>>;
+
+type Shard<T> = SliceMap<Subscribers<T>, Hashing>;
+
+/// which subscribers are interested in which topics. the event loop makes every change, once,
+/// and all the workers read it to fan out notifications. topics are spread over shards that are
+/// locked separately, and a read only holds its shard's lock for the lookup, so readers never
+/// wait on each other and a change only holds up readers of topics in the same shard, for as
+/// long as it takes to insert or remove one subscriber
+pub struct TopicIndex<T = Arc<Peer>> {
+    shards: Vec<RwLock<Shard<T>>>,
+    hashing: Hashing
+}
+
+impl<T: Subscriber> TopicIndex<T> {
+    /// an empty index of {shards} shards (rounded up to a power of two) hashing topics with
+    /// {hashing}
+    pub fn new(shards: usize, hashing: Hashing) -> TopicIndex<T> {
+        TopicIndex {
+            shards: (0..shards.max(1).next_power_of_two()).map(|_| RwLock::new(SliceMap::with_hasher(hashing.clone()))).collect(),
+            hashing
+        }
+    }
+
+    /// the subscribers of {topic}, if it has any
+    pub fn subscribers(&self, topic: &[u8]) -> Option<Subscribers<T>> {
+        let hash = self.hashing.hash_one(topic);
+        self.read(hash).get_hashed(hash, topic).cloned()
+    }
+
+    /// adds {subscriber} to {topic}, false if it was already subscribed
+    pub fn subscribe(&self, topic: &[u8], subscriber: T) -> bool {
+        let hash = self.hashing.hash_one(topic);
+        let mut shard = self.write(hash);
+        match shard.entry_hashed(hash, topic) {
+            Entry::Occupied(mut e) => {
+                let at = match e.get().binary_search_by_key(&subscriber.id(), Subscriber::id) {
+                    Ok(_) => return false,
+                    Err(at) => at
+                };
+                Arc::make_mut(e.get_mut()).insert(at, subscriber);
+            }
+            Entry::Vacant(e) => {
+                e.insert(Arc::new(vec![subscriber]));
+            }
+        }
+        true
+    }
+
+    /// removes the subscriber with {id} from {topic}, and the topic once it has no subscribers
+    /// left. false if it wasn't subscribed
+    pub fn unsubscribe(&self, topic: &[u8], id: usize) -> bool {
+        let hash = self.hashing.hash_one(topic);
+        let mut shard = self.write(hash);
+        let mut e = match shard.entry_hashed(hash, topic) {
+            Entry::Occupied(e) => e,
+            Entry::Vacant(_) => return false
+        };
+        let at = match e.get().binary_search_by_key(&id, Subscriber::id) {
+            Ok(at) => at,
+            Err(_) => return false
+        };
+        if e.get().len() == 1 {
+            e.remove();
+        } else {
+            Arc::make_mut(e.get_mut()).remove(at);
+        }
+        true
+    }
+
+    /// every topic with its number of subscribers, in no particular order. shards are read one
+    /// at a time, so this is not a consistent snapshot while subscriptions change
+    pub fn topics(&self) -> Vec<(Vec<u8>, usize)> {
+        let mut topics = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap_or_else(|e| e.into_inner());
+            topics.extend(shard.iter().map(|(topic, subscribers)| (topic.to_vec(), subscribers.len())));
+        }
+        topics
+    }
+
+    /// the {n} topics with the most subscribers, most first. shards are read one at a time as in
+    /// {topics}, and a topic is only copied out if it makes the cut
+    pub fn busiest(&self, n: usize) -> Vec<(Vec<u8>, usize)> {
+        // the least subscribed topic so far is at the top, it is the one to go
+        let mut busiest = BinaryHeap::with_capacity(n + 1);
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap_or_else(|e| e.into_inner());
+            for (topic, subscribers) in shard.iter() {
+                let full = busiest.len() >= n;
+                if full && busiest.peek().is_none_or(|&Reverse((least, _))| least >= subscribers.len()) {
+                    continue;
+                }
+                busiest.push(Reverse((subscribers.len(), topic.to_vec())));
+                if full {
+                    busiest.pop();
+                }
+            }
+        }
+        busiest.into_sorted_vec().into_iter().map(|Reverse((count, topic))| (topic, count)).collect()
+    }
+
+    /// the number of topics with subscribers
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap_or_else(|e| e.into_inner()).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the shard of the topic with {hash}. it is picked with the high bits of the hash, the
+    /// shard's map uses the low ones, and is handed the same hash so the topic is hashed once
+    fn shard(&self, hash: u64) -> &RwLock<Shard<T>> {
+        &self.shards[(hash >> 40) as usize & (self.shards.len() - 1)]
+    }
+
+    // a change is a single insert or remove, so a panic while a lock is held can't leave a shard
+    // half changed and a poisoned lock is still good to use
+    fn read(&self, hash: u64) -> RwLockReadGuard<'_, Shard<T>> {
+        self.shard(hash).read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self, hash: u64) -> RwLockWriteGuard<'_, Shard<T>> {
+        self.shard(hash).write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicIndex;
+    use std::sync::Arc;
+    use slice_map::Hashing;
+
+    #[test]
+    fn subscriptions_are_listed_once() {
+        let index = TopicIndex::<usize>::new(4, Hashing::Fast);
+        assert!(index.subscribe(b"a", 1));
+        assert!(index.subscribe(b"a", 2));
+        assert!(!index.subscribe(b"a", 1));
+        assert!(index.subscribe(b"b", 1));
+        assert!(index.subscribe(b"a", 0));
+        assert_eq!(*index.subscribers(b"a").unwrap(), vec![0, 1, 2]);
+        assert_eq!(index.len(), 2);
+
+        let mut topics = index.topics();
+        topics.sort();
+        assert_eq!(topics, vec![(b"a".to_vec(), 3), (b"b".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn the_busiest_topics_come_first() {
+        let index = TopicIndex::<usize>::new(4, Hashing::Fast);
+        for (subscribers, topic) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            for id in 0..subscribers + 1 {
+                index.subscribe(&topic[..], id);
+            }
+        }
+        assert_eq!(index.busiest(2), vec![(b"d".to_vec(), 4), (b"c".to_vec(), 3)]);
+        assert_eq!(index.busiest(10).len(), 4);
+        assert!(index.busiest(0).is_empty());
+    }
+
+    #[test]
+    fn readers_keep_the_list_they_got() {
+        let index = TopicIndex::<usize>::new(4, Hashing::Fast);
+        index.subscribe(b"a", 1);
+        index.subscribe(b"a", 2);
+        let before = index.subscribers(b"a").unwrap();
+
+        assert!(index.unsubscribe(b"a", 1));
+        assert!(!index.unsubscribe(b"a", 1));
+        assert_eq!(*before, vec![1, 2]);
+        assert_eq!(*index.subscribers(b"a").unwrap(), vec![2]);
+
+        // with nobody holding on to it a list is changed where it is
+        drop(before);
+        let list = Arc::as_ptr(&index.subscribers(b"a").unwrap());
+        assert!(index.subscribe(b"a", 3));
+        assert!(index.unsubscribe(b"a", 2));
+        assert_eq!(Arc::as_ptr(&index.subscribers(b"a").unwrap()), list);
+
+        // the last one out takes the topic with it
+        assert!(index.unsubscribe(b"a", 3));
+        assert!(index.subscribers(b"a").is_none());
+        assert!(index.is_empty());
+    }
+}
@@ -0,0 +1,387 @@
+use std::{fmt, fs, io, thread};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use libc;
+use mio::tcp::{TcpStream, TcpListener};
+use mio::unix::{UnixStream, UnixListener};
+use tls::TlsStream;
+
+/// where a listener accepts connections. written as "host:port" or "unix:/path/to/socket"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+impl ListenAddr {
+    /// the socket address of a tcp listener
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match *self {
+            ListenAddr::Tcp(addr) => Some(addr),
+            ListenAddr::Unix(_) => None
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> ListenAddr {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ListenAddr, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("missing unix socket path".to_owned());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(ListenAddr::Tcp).map_err(|_| format!("not a valid address: {:?}", s))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(ref path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+/// a bound, non blocking listener
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf)
+}
+
+impl ListenSocket {
+    /// binds a unix socket, replacing a stale socket file left behind by a previous run
+    pub fn bind_unix(path: PathBuf) -> io::Result<ListenSocket> {
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+            }
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        Ok(ListenSocket::Unix(listener, path))
+    }
+
+    /// accepts one pending connection, Ok(None) if there are none left
+    pub fn accept(&self) -> io::Result<Option<(Stream, String)>> {
+        match *self {
+            ListenSocket::Tcp(ref l) => Ok(l.accept()?.map(|(s, addr)| (Stream::Tcp(s), addr.to_string()))),
+            ListenSocket::Unix(ref l, ref path) => {
+                Ok(l.accept()?.map(|s| (Stream::Unix(s), format!("unix:{}", path.display()))))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match *self {
+            ListenSocket::Tcp(ref l) => l.local_addr().map(ListenAddr::Tcp),
+            ListenSocket::Unix(_, ref path) => Ok(ListenAddr::Unix(path.clone()))
+        }
+    }
+
+    /// removes the socket file of a unix listener
+    pub fn cleanup(&self) {
+        if let ListenSocket::Unix(_, ref path) = *self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl AsRawFd for ListenSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            ListenSocket::Tcp(ref l) => l.as_raw_fd(),
+            ListenSocket::Unix(ref l, _) => l.as_raw_fd()
+        }
+    }
+}
+
+/// a non blocking client connection over any of the supported transports
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream>)
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush()
+        }
+    }
+}
+
+impl Stream {
+    /// whether the stream holds on to written data it could not pass on to the socket yet
+    fn is_holding(&self) -> bool {
+        match *self {
+            Stream::Tls(ref s) => s.is_holding(),
+            _ => false
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref s) => s.as_raw_fd(),
+            Stream::Unix(ref s) => s.as_raw_fd(),
+            Stream::Tls(ref s) => s.as_raw_fd()
+        }
+    }
+}
+
+/// the server side of a client connection. shared between the event loop, which reads messages
+/// off of it, and the workers, which write notifications to it. the socket stays open for as long
+/// as anyone holds on to the peer, so a recycled file descriptor can never be written to by mistake
+pub struct Peer {
+    /// unique for the lifetime of the server
+    id: usize,
+    addr: String,
+    fd: RawFd,
+    stream: Mutex<Stream>,
+
+    /// frames are queued whole, so frames from different writers don't interleave
+    outbox: Mutex<Outbox>,
+    closed: AtomicBool,
+
+    /// the FEATURES the client asked for
+    features: AtomicU8
+}
+
+/// what was written to a peer that the socket has not taken yet
+#[derive(Default)]
+struct Outbox {
+    bytes: Vec<u8>,
+
+    /// how much the socket has taken over the life of the connection
+    written: u64
+}
+
+impl Peer {
+    pub fn new(id: usize, stream: Stream, addr: String) -> Peer {
+        Peer {
+            id,
+            addr,
+            fd: stream.as_raw_fd(),
+            stream: Mutex::new(stream),
+            outbox: Mutex::new(Outbox::default()),
+            closed: AtomicBool::new(false),
+            features: AtomicU8::new(0)
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the remote address, for display
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// the socket's file descriptor, for registering with the event loop
+    pub fn fd(&self) -> &RawFd {
+        &self.fd
+    }
+
+    /// the FEATURE_* bits the client asked for
+    pub fn features(&self) -> u8 {
+        self.features.load(Ordering::Relaxed)
+    }
+
+    pub fn set_features(&self, features: u8) {
+        self.features.store(features, Ordering::Relaxed);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// the underlying stream, for reading. hold on to it as briefly as possible
+    pub fn stream(&self) -> MutexGuard<'_, Stream> {
+        self.stream.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn outbox(&self) -> MutexGuard<'_, Outbox> {
+        self.outbox.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// queues a whole frame behind whatever is queued already and writes out as much as the
+    /// socket takes, without ever waiting for it. what is left is written by flush. fails if the
+    /// connection is unusable, in which case the peer is closed
+    pub fn queue(&self, frame: &[u8]) -> io::Result<()> {
+        let mut outbox = self.outbox();
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer is closed"));
+        }
+        outbox.bytes.extend_from_slice(frame);
+        self.write_out(&mut outbox).map(|_| ())
+    }
+
+    /// writes out as much of what is queued as the socket takes, without waiting for it
+    pub fn flush(&self) -> io::Result<()> {
+        self.write_out(&mut self.outbox()).map(|_| ())
+    }
+
+    /// how many bytes are queued
+    pub fn queued(&self) -> usize {
+        self.outbox().bytes.len()
+    }
+
+    /// whether everything queued has made it to the socket
+    pub fn is_flushed(&self) -> bool {
+        let outbox = self.outbox();
+        outbox.bytes.is_empty() && !self.stream().is_holding()
+    }
+
+    /// writes a whole frame, waiting for room in the socket buffer if need be. only for the
+    /// workers, the event loop queues instead. a frame that fails part way through leaves the
+    /// client unable to find the next one, so the peer is closed
+    pub fn send(&self, frame: &[u8]) -> Result<(), SendError> {
+        let start = {
+            let mut outbox = self.outbox();
+            if self.is_closed() {
+                return Err(SendError { written: 0, error: io::Error::new(io::ErrorKind::BrokenPipe, "peer is closed") });
+            }
+            let start = outbox.written + outbox.bytes.len() as u64;
+            outbox.bytes.extend_from_slice(frame);
+            start
+        };
+        let end = start + frame.len() as u64;
+        loop {
+            let mut outbox = self.outbox();
+            let flushed = self.write_out(&mut outbox);
+            let written = outbox.written.saturating_sub(start).min(frame.len() as u64) as usize;
+            match flushed {
+                // later frames are written out by whoever queued them, along with whatever a TLS
+                // session still holds of this one
+                Ok(flushed) if outbox.written >= end && (flushed || !outbox.bytes.is_empty()) => return Ok(()),
+                Ok(_) => (),
+                Err(error) => return Err(SendError { written, error })
+            }
+            // unlocked while waiting, so the event loop can still queue
+            drop(outbox);
+            self.wait().map_err(|error| SendError { written, error })?;
+        }
+    }
+
+    /// writes out the front of {outbox}, Ok(true) once all of it is out. a write that fails for
+    /// any reason but a full socket buffer closes the peer
+    fn write_out(&self, outbox: &mut Outbox) -> io::Result<bool> {
+        let mut stream = self.stream();
+        let mut index = 0;
+        let result = loop {
+            if index == outbox.bytes.len() {
+                // TLS sessions may still hold on to part of it
+                break stream.flush();
+            }
+            match stream.write(&outbox.bytes[index..]) {
+                Ok(0) => break Err(io::Error::new(io::ErrorKind::WriteZero, "peer stopped accepting data")),
+                Ok(n) => index += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e)
+            }
+        };
+        outbox.bytes.drain(..index);
+        outbox.written += index as u64;
+        match result {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+
+    /// gives the client a moment to make room in the socket buffer
+    fn wait(&self) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer is closed"));
+        }
+        //this is dangerous as it will hold up the worker on a client that never reads. perhaps
+        //enforce a max timeout or retry limit
+        thread::yield_now();
+        Ok(())
+    }
+
+    /// shuts the connection down. pending and future sends fail, the file descriptor is released
+    /// once the last reference to the peer is dropped
+    pub fn close(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            // SAFETY: {fd} belongs to {stream}, which the peer owns and only closes when it is
+            // dropped, so the descriptor is still open and can't have been reused by another socket.
+            // shutdown leaves it open, it only stops traffic in both directions
+            unsafe { libc::shutdown(self.fd, libc::SHUT_RDWR) };
+        }
+    }
+}
+
+/// a frame that could not be sent
+#[derive(Debug)]
+pub struct SendError {
+    /// how much of the frame was written before the failure
+    pub written: usize,
+    pub error: io::Error
+}
+
+impl SendError {
+    /// whether part of the frame made it out
+    pub fn is_partial(&self) -> bool {
+        self.written > 0
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (after {} bytes)", self.error, self.written)
+    }
+}
+
+impl From<SendError> for io::Error {
+    fn from(e: SendError) -> io::Error {
+        e.error
+    }
+}
+
+impl AsRawFd for Peer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
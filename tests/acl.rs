@@ -0,0 +1,63 @@
+extern crate rqueue;
+
+mod common;
+
+use std::io::ErrorKind;
+use std::time::Duration;
+use rqueue::acl::Acl;
+use rqueue::client::Message;
+use rqueue::protocol::ERR_PERMISSION_DENIED;
+use rqueue::server::ServerHandle;
+use common::{sync, connect_as};
+
+const ACL: &str = "[user.billing]\npublish = [\"invoices.*\"]\nsubscribe = [\"payments.>\"]\n\
+                   [user.auditor]\nsubscribe = [\"invoices.*\", \"payments.>\"]\n";
+
+fn spawn_server() -> ServerHandle {
+    common::spawn(common::with_users(common::builder(), &["billing", "auditor"], ACL))
+}
+
+fn assert_denied(message: Message) {
+    assert_eq!(message.error().unwrap().0, ERR_PERMISSION_DENIED);
+}
+
+#[test]
+fn denied_messages_are_dropped_and_reported() {
+    let handle = spawn_server();
+    let mut auditor = connect_as(&handle, "auditor");
+    auditor.subscribe(b"invoices.eu").unwrap();
+    sync(&mut auditor);
+
+    let mut billing = connect_as(&handle, "billing");
+    billing.subscribe(b"invoices.eu").unwrap();
+    assert_denied(billing.next_message().unwrap());
+    billing.publish(b"payments.eu", b"not yours").unwrap();
+    assert_denied(billing.next_message().unwrap());
+
+    // the connection survives a denial
+    billing.publish(b"invoices.eu", b"allowed").unwrap();
+    assert_eq!(auditor.next_message().unwrap().content(), Some(&b"allowed"[..]));
+}
+
+#[test]
+fn reloading_revokes_subscriptions() {
+    let handle = spawn_server();
+    let mut auditor = connect_as(&handle, "auditor");
+    auditor.subscribe(b"invoices.eu").unwrap();
+    auditor.subscribe(b"payments.eu").unwrap();
+    sync(&mut auditor);
+
+    handle.set_acl(Acl::parse("[user.billing]\npublish = [\"invoices.*\", \"payments.*\"]\n\
+                               [user.auditor]\nsubscribe = [\"payments.>\"]\n").unwrap()).unwrap();
+    // the subscription is gone by the time the notice is read
+    assert_denied(auditor.next_message().unwrap());
+
+    let mut billing = connect_as(&handle, "billing");
+    billing.publish(b"invoices.eu", b"revoked").unwrap();
+    billing.publish(b"payments.eu", b"still allowed").unwrap();
+    assert_eq!(auditor.next_message().unwrap().content(), Some(&b"still allowed"[..]));
+
+    auditor.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let kind = auditor.next_message().unwrap_err().kind();
+    assert!(kind == ErrorKind::WouldBlock || kind == ErrorKind::TimedOut);
+}
@@ -0,0 +1,105 @@
+extern crate rqueue;
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::Duration;
+use rqueue::admin::{Request, TopicInfo};
+use rqueue::protocol::{self, AUTH_OK};
+use rqueue::server::ServerHandle;
+use common::{sync, read_frame, connect_as};
+
+fn spawn_server() -> ServerHandle {
+    common::spawn(common::with_users(common::builder(), &["ops", "app"],
+                                     "[user.ops]\nadmin = true\n[user.app]\npublish = [\">\"]\nsubscribe = [\">\"]\n"))
+}
+
+#[test]
+fn only_admins_may_ask() {
+    let handle = spawn_server();
+    let mut app = connect_as(&handle, "app");
+    assert_eq!(app.list_clients().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert_eq!(app.disconnect(0).unwrap_err().kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn admins_inspect_and_disconnect_clients() {
+    let handle = spawn_server();
+    let mut app = connect_as(&handle, "app");
+    app.subscribe(b"orders").unwrap();
+    app.subscribe(b"prices").unwrap();
+    let mut other = connect_as(&handle, "app");
+    other.subscribe(b"prices").unwrap();
+    sync(&mut app);
+    sync(&mut other);
+
+    let mut ops = connect_as(&handle, "ops");
+    let clients = ops.list_clients().unwrap();
+    assert_eq!(clients.len(), 3);
+    assert!(clients.iter().all(|c| c.addr.starts_with("127.0.0.1:")));
+    let app_info = &clients[0];
+    assert_eq!(app_info.user.as_deref(), Some("app"));
+    assert_eq!(app_info.subscriptions, 2);
+
+    assert_eq!(ops.list_topics().unwrap(), vec![TopicInfo { topic: b"orders".to_vec(), subscribers: 1 },
+                                                 TopicInfo { topic: b"prices".to_vec(), subscribers: 2 }]);
+    assert_eq!(ops.subscriptions_of(app_info.id).unwrap(), vec![b"orders".to_vec(), b"prices".to_vec()]);
+
+    ops.disconnect(app_info.id).unwrap();
+    assert_eq!(app.next_message().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(ops.list_clients().unwrap().len(), 2);
+    assert_eq!(ops.disconnect(app_info.id).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(ops.subscriptions_of(app_info.id).unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn publishers_are_told_apart_by_what_they_delivered() {
+    let handle = spawn_server();
+    let mut first = connect_as(&handle, "app");
+    let mut second = connect_as(&handle, "app");
+    first.subscribe(b"orders").unwrap();
+    second.subscribe(b"orders").unwrap();
+    sync(&mut first);
+    sync(&mut second);
+
+    // a PUBACK goes out once the delivery has been counted
+    let mut publisher = connect_as(&handle, "app");
+    for _ in 0..3 {
+        publisher.publish_confirmed(b"orders", b"1").unwrap();
+    }
+    publisher.wait_confirmed().unwrap();
+    for _ in 0..3 {
+        first.next_message().unwrap();
+        second.next_message().unwrap();
+    }
+
+    let mut ops = connect_as(&handle, "ops");
+    let clients = ops.list_clients().unwrap();
+    let info = clients.iter().max_by_key(|c| c.delivered).unwrap();
+    assert_eq!((info.delivered, info.in_flight), (6, 0));
+    assert_eq!(clients.iter().filter(|c| c.delivered > 0).count(), 1);
+}
+
+#[test]
+fn admins_that_never_read_their_replies_hold_up_no_one() {
+    let handle = spawn_server();
+    let mut app = connect_as(&handle, "app");
+    app.subscribe(b"orders").unwrap();
+    sync(&mut app);
+
+    let mut ops = common::connect_raw(handle.local_addr());
+    ops.write_all(&protocol::auth_message(b"", b"ops")).unwrap();
+    assert_eq!(read_frame(&mut ops).0, AUTH_OK);
+
+    // asks until the server stops reading, without reading a single reply
+    let mut writer = ops.try_clone().unwrap();
+    writer.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+    let request = Request::ListClients.to_frame();
+    let flood = thread::spawn(move || while writer.write_all(&request).is_ok() {});
+    flood.join().unwrap();
+
+    let mut publisher = connect_as(&handle, "app");
+    publisher.publish(b"orders", b"served").unwrap();
+    assert_eq!(app.next_message().unwrap().content(), Some(&b"served"[..]));
+}
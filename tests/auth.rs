@@ -0,0 +1,88 @@
+extern crate rqueue;
+extern crate bcrypt;
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use rqueue::auth::{Credentials, hash_token};
+use rqueue::protocol::{self, ERROR, AUTH_OK, NOTIFICATION, ERR_AUTH_REQUIRED};
+use rqueue::server::{Server, ServerHandle, ListenerConfig};
+use common::{sync, sync_raw, read_frame, connect_to as connect};
+
+fn spawn_server() -> ServerHandle {
+    // the minimum cost keeps the tests fast
+    let credentials = Credentials::parse(&format!("[[user]]\nname = \"alice\"\npassword = \"{}\"\n\
+                                                   [[user]]\nname = \"ingest\"\ntoken = \"{}\"\n",
+                                                  bcrypt::hash("hunter2", 4).unwrap(), hash_token("t0k3n"))).unwrap();
+    common::spawn(common::builder()
+        .listen(ListenerConfig::new(common::localhost()).require_auth(false))
+        .credentials(credentials))
+}
+
+#[test]
+fn unauthenticated_clients_are_told_and_disconnected() {
+    let handle = spawn_server();
+    let mut client = connect(handle.local_addr());
+    client.subscribe(b"t").unwrap();
+
+    let reply = client.next_message().unwrap();
+    assert_eq!(reply.m_type, ERROR);
+    assert_eq!(reply.error().unwrap().0, ERR_AUTH_REQUIRED);
+    assert_eq!(client.next_message().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn wrong_credentials_are_rejected() {
+    let handle = spawn_server();
+    let mut client = connect(handle.local_addr());
+    assert_eq!(client.authenticate("alice", "hunter3").unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+    let mut client = connect(handle.local_addr());
+    assert_eq!(client.authenticate_token("guess").unwrap_err().kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn authenticated_clients_exchange_notifications() {
+    let handle = spawn_server();
+    let mut subscriber = connect(handle.local_addr());
+    subscriber.authenticate("alice", "hunter2").unwrap();
+    subscriber.subscribe(b"t").unwrap();
+    sync(&mut subscriber);
+
+    let mut publisher = connect(handle.local_addr());
+    publisher.authenticate_token("t0k3n").unwrap();
+    publisher.publish(b"t", b"signed in").unwrap();
+    assert_eq!(subscriber.next_message().unwrap().content(), Some(&b"signed in"[..]));
+
+    // the second listener lets anyone in
+    let mut anonymous = connect(handle.local_addrs()[1].tcp().unwrap());
+    anonymous.publish(b"t", b"anonymous").unwrap();
+    assert_eq!(subscriber.next_message().unwrap().content(), Some(&b"anonymous"[..]));
+}
+
+#[test]
+fn messages_sent_right_after_an_auth_wait_for_it() {
+    let handle = spawn_server();
+    let mut subscriber = common::connect_raw(handle.local_addr());
+    let mut frames = protocol::auth_message(b"alice", b"hunter2");
+    frames.extend(protocol::subscribe_message(b"t"));
+    subscriber.write_all(&frames).unwrap();
+    assert_eq!(read_frame(&mut subscriber).0, AUTH_OK);
+    sync_raw(&mut subscriber);
+
+    let mut publisher = connect(handle.local_addr());
+    publisher.authenticate_token("t0k3n").unwrap();
+    publisher.publish(b"t", b"pipelined").unwrap();
+    let (m_type, payload) = read_frame(&mut subscriber);
+    assert_eq!(m_type, NOTIFICATION);
+    assert!(payload.ends_with(b"pipelined"));
+}
+
+#[test]
+fn requiring_auth_without_credentials_fails_the_build() {
+    let result = Server::builder()
+        .bind(common::localhost())
+        .require_auth(true)
+        .build();
+    assert!(result.is_err());
+}
@@ -0,0 +1,101 @@
+//! fixtures shared by the integration tests. every test file compiles its own copy of this module
+//! and none of them uses all of it
+#![allow(dead_code)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use rqueue::acl::Acl;
+use rqueue::auth::{Credentials, hash_token};
+use rqueue::client::Client;
+use rqueue::protocol::{self, PREAMBLE_SZ, PUBNACK, ERR_PERMISSION_DENIED};
+use rqueue::server::{Server, ServerBuilder, ServerHandle};
+
+/// how long a test waits for the server before failing
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// reserved topics are read only, so a PUBLISH to this one is always refused and never delivered
+const SYNC_TOPIC: &[u8] = b"$SYS.sync";
+
+/// an ephemeral port on the loopback interface
+pub fn localhost() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 0))
+}
+
+/// a server on {localhost} with two workers, for the test to add to
+pub fn builder() -> ServerBuilder {
+    Server::builder()
+        .bind(localhost())
+        .workers(2)
+}
+
+pub fn spawn(builder: ServerBuilder) -> ServerHandle {
+    builder.build().unwrap().spawn().unwrap()
+}
+
+/// {users} that authenticate with their own name as a token, allowed what {acl} says
+pub fn with_users(builder: ServerBuilder, users: &[&str], acl: &str) -> ServerBuilder {
+    let credentials = users.iter()
+        .map(|user| format!("[[user]]\nname = \"{}\"\ntoken = \"{}\"\n", user, hash_token(user)))
+        .collect::<String>();
+    builder.credentials(Credentials::parse(&credentials).unwrap())
+           .acl(Acl::parse(acl).unwrap())
+}
+
+pub fn connect(handle: &ServerHandle) -> Client {
+    connect_to(handle.local_addr())
+}
+
+/// a client that gives up on a reply after {TIMEOUT}
+pub fn connect_to(addr: SocketAddr) -> Client {
+    let client = Client::connect(addr).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client
+}
+
+/// a client authenticated with the token {user}, see {with_users}
+pub fn connect_as(handle: &ServerHandle, user: &str) -> Client {
+    let mut client = connect(handle);
+    client.authenticate_token(user).unwrap();
+    client
+}
+
+/// a bare socket, for tests that look at the frames themselves
+pub fn connect_raw(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream
+}
+
+/// the type and payload of the next frame on {stream}
+pub fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut preamble = [0; PREAMBLE_SZ];
+    stream.read_exact(&mut preamble).unwrap();
+    let mut payload = vec![0; protocol::u8_2_to_usize(&preamble[..2])];
+    stream.read_exact(&mut payload).unwrap();
+    (preamble[2], payload)
+}
+
+/// a round trip to the server. it handles the messages of a client in the order they were sent,
+/// so whatever came before, a SUBSCRIBE say, has taken effect once this returns
+pub fn sync(client: &mut Client) {
+    client.publish_confirmed(SYNC_TOPIC, b"").unwrap();
+    assert_eq!(client.wait_confirmed().unwrap_err().kind(), ErrorKind::PermissionDenied);
+}
+
+/// {sync} for a bare socket, which must not have anything else to read
+pub fn sync_raw(stream: &mut TcpStream) {
+    stream.write_all(&protocol::publish_message(0, SYNC_TOPIC, b"")).unwrap();
+    let (m_type, payload) = read_frame(stream);
+    assert_eq!((m_type, payload.get(4).cloned()), (PUBNACK, Some(ERR_PERMISSION_DENIED)));
+}
+
+/// polls {check} until it holds, for state that has no reply to wait on
+pub fn eventually<F: FnMut() -> bool>(what: &str, mut check: F) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !check() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,108 @@
+extern crate rqueue;
+
+mod common;
+
+use std::io::Write;
+use rqueue::protocol::{self, RawMessage, NOTIFICATION, HEADERS, ERROR, PUBNACK, ERR_BAD_REQUEST, HEADER_CONTENT_TYPE, HEADER_TRACE_ID};
+use common::{sync, connect, read_frame};
+
+#[test]
+fn header_blocks_round_trip() {
+    let headers: &[(&[u8], &[u8])] = &[(HEADER_CONTENT_TYPE, b"text/plain"), (b"empty", b""), (b"", b"no key")];
+    let frame = protocol::headers_message(b"orders", headers, b"content");
+    assert_eq!(frame[2], HEADERS);
+
+    let (topic, block, content) = protocol::split_headers(&frame[protocol::PREAMBLE_SZ..]).unwrap();
+    assert_eq!((topic, content), (&b"orders"[..], &b"content"[..]));
+    assert_eq!(protocol::parse_headers(block).unwrap(), headers);
+    assert_eq!(protocol::strip_headers(&frame).unwrap(), protocol::notify_message(b"orders", b"content"));
+
+    // confirmed, the same HEADERS with a sequence number in front
+    let published = RawMessage::from_frame(&protocol::publish_headers_message(7, b"orders", headers, b"content"), None).published().unwrap();
+    assert_eq!((published.m_type, published.seq), (HEADERS, Some(7)));
+    assert_eq!(&published.bytes[..published.length], &frame[..]);
+
+    // a value running past the end of the block
+    assert_eq!(protocol::parse_headers(&block[..block.len() - 1]), None);
+    assert_eq!(protocol::parse_headers(&[]), Some(vec![]));
+}
+
+#[test]
+fn only_subscribers_that_ask_get_the_headers() {
+    let handle = common::spawn(common::builder());
+    let mut modern = connect(&handle);
+    modern.enable_headers().unwrap();
+    modern.subscribe(b"orders").unwrap();
+    let mut legacy = connect(&handle);
+    legacy.subscribe(b"orders").unwrap();
+    sync(&mut modern);
+    sync(&mut legacy);
+
+    let mut publisher = connect(&handle);
+    publisher.publish_with_headers(b"orders", &[(HEADER_TRACE_ID, b"abc"), (b"x-region", b"eu")], b"order 1").unwrap();
+
+    let message = modern.next_message().unwrap();
+    assert_eq!(message.m_type, HEADERS);
+    assert_eq!((message.topic(), message.content()), (Some(&b"orders"[..]), Some(&b"order 1"[..])));
+    assert_eq!(message.header(HEADER_TRACE_ID), Some(&b"abc"[..]));
+    assert_eq!(message.headers().unwrap().len(), 2);
+
+    let message = legacy.next_message().unwrap();
+    assert_eq!(message.m_type, NOTIFICATION);
+    assert_eq!((message.topic(), message.content()), (Some(&b"orders"[..]), Some(&b"order 1"[..])));
+    assert_eq!(message.headers(), None);
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn confirmed_publishes_keep_their_headers() {
+    let handle = common::spawn(common::builder());
+    let mut modern = connect(&handle);
+    modern.enable_headers().unwrap();
+    modern.subscribe(b"orders").unwrap();
+    let mut legacy = connect(&handle);
+    legacy.subscribe(b"orders").unwrap();
+    sync(&mut modern);
+    sync(&mut legacy);
+
+    let mut publisher = connect(&handle);
+    publisher.publish_confirmed_with_headers(b"orders", &[(HEADER_TRACE_ID, b"abc")], b"order 1").unwrap();
+    publisher.wait_confirmed().unwrap();
+
+    let message = modern.next_message().unwrap();
+    assert_eq!((message.m_type, message.content()), (HEADERS, Some(&b"order 1"[..])));
+    assert_eq!(message.header(HEADER_TRACE_ID), Some(&b"abc"[..]));
+
+    let message = legacy.next_message().unwrap();
+    assert_eq!((message.m_type, message.content()), (NOTIFICATION, Some(&b"order 1"[..])));
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn malformed_header_blocks_are_rejected() {
+    let handle = common::spawn(common::builder());
+    let mut subscriber = connect(&handle);
+    subscriber.enable_headers().unwrap();
+    subscriber.subscribe(b"orders").unwrap();
+    sync(&mut subscriber);
+
+    // the one header in the block claims a 5 byte value that is not there
+    let mut publisher = common::connect_raw(handle.local_addr());
+    publisher.write_all(&[0, 13, HEADERS, 6, b'o', b'r', b'd', b'e', b'r', b's', 0, 4, 1, b'k', 0, 5]).unwrap();
+    let (m_type, payload) = read_frame(&mut publisher);
+    assert_eq!((m_type, payload[0]), (ERROR, ERR_BAD_REQUEST));
+
+    // confirmed, it is answered by sequence number
+    let mut frame = protocol::publish_headers_message(9, b"orders", &[(b"k", b"value")], b"");
+    frame.truncate(frame.len() - 1);
+    frame[1] -= 1;
+    publisher.write_all(&frame).unwrap();
+    let (m_type, payload) = read_frame(&mut publisher);
+    assert_eq!((m_type, &payload[..4], payload[4]), (PUBNACK, &9u32.to_be_bytes()[..], ERR_BAD_REQUEST));
+
+    // the connection is still good
+    publisher.write_all(&protocol::headers_message(b"orders", &[], b"fine")).unwrap();
+    let message = subscriber.next_message().unwrap();
+    assert_eq!((message.m_type, message.content()), (HEADERS, Some(&b"fine"[..])));
+    handle.shutdown().unwrap();
+}
@@ -0,0 +1,96 @@
+extern crate rqueue;
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::time::Duration;
+use rqueue::limits::RateLimit;
+use rqueue::protocol::{self, NOTIFICATION, PUBNACK, ERR_TOO_MANY_CONNECTIONS, ERR_TOO_MANY_SUBSCRIPTIONS, ERR_RATE_LIMITED};
+use common::{sync, sync_raw, read_frame, connect};
+
+#[test]
+fn full_servers_say_why_they_refuse() {
+    let handle = common::spawn(common::builder().max_connections(1));
+    let mut first = connect(&handle);
+    sync(&mut first);
+
+    let mut second = connect(&handle);
+    assert_eq!(second.next_message().unwrap().error().unwrap().0, ERR_TOO_MANY_CONNECTIONS);
+    assert_eq!(second.next_message().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert!(handle.metrics().render().contains("rqueue_limited_total{limit=\"connections\"} 1\n"));
+}
+
+#[test]
+fn subscriptions_over_the_limit_are_dropped() {
+    let handle = common::spawn(common::builder().max_subscriptions(2));
+    let mut subscriber = connect(&handle);
+    for topic in &[&b"a"[..], b"b", b"a", b"c"] {
+        subscriber.subscribe(topic).unwrap();
+    }
+    let (code, reason) = subscriber.next_message().unwrap().error().unwrap();
+    assert_eq!(code, ERR_TOO_MANY_SUBSCRIPTIONS);
+    assert!(reason.contains("2 subscriptions"), "{}", reason);
+
+    // the client stays connected with the subscriptions it had
+    sync(&mut subscriber);
+    let mut publisher = connect(&handle);
+    publisher.publish(b"c", b"dropped").unwrap();
+    publisher.publish(b"b", b"kept").unwrap();
+    let message = subscriber.next_message().unwrap();
+    assert_eq!(message.content(), Some(&b"kept"[..]));
+}
+
+#[test]
+fn fast_publishers_are_throttled_and_told_once() {
+    let handle = common::spawn(common::builder()
+        .rate_limit(RateLimit { messages_per_sec: Some(5), bytes_per_sec: None }));
+    let mut subscriber = connect(&handle);
+    subscriber.subscribe(b"t").unwrap();
+    sync(&mut subscriber);
+
+    let mut publisher = connect(&handle);
+    for _ in 0..20 {
+        publisher.publish(b"t", b"burst").unwrap();
+    }
+    assert_eq!(publisher.next_message().unwrap().error().unwrap().0, ERR_RATE_LIMITED);
+
+    // the burst allowance is what gets through, the SUBSCRIBE and sync counted against the subscriber
+    subscriber.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut delivered = 0;
+    while subscriber.next_message().is_ok() {
+        delivered += 1;
+    }
+    assert_eq!(delivered, 5);
+
+    // a single error for the whole bout
+    publisher.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert!(publisher.next_message().is_err());
+    assert!(handle.metrics().render().contains("rqueue_limited_total{limit=\"messages\"} 15\n"));
+}
+
+#[test]
+fn clients_that_stop_mid_frame_hold_up_no_one() {
+    let handle = common::spawn(common::builder());
+    let mut stalled = common::connect_raw(handle.local_addr());
+    stalled.write_all(&protocol::subscribe_message(b"t")).unwrap();
+    sync_raw(&mut stalled);
+
+    // one byte of a preamble, then nothing for a while
+    let frame = protocol::publish_message(0, b"$SYS.sync", b"");
+    stalled.write_all(&frame[..1]).unwrap();
+    let mut publisher = connect(&handle);
+    sync(&mut publisher);
+    // the workers can still write to the stalled client
+    publisher.publish(b"t", b"meanwhile").unwrap();
+    let (m_type, payload) = read_frame(&mut stalled);
+    assert_eq!(m_type, NOTIFICATION);
+    assert!(payload.ends_with(b"meanwhile"));
+
+    // and part of the payload
+    stalled.write_all(&frame[1..5]).unwrap();
+    sync(&mut publisher);
+
+    // the frame is picked up where it was left off
+    stalled.write_all(&frame[5..]).unwrap();
+    assert_eq!(read_frame(&mut stalled).0, PUBNACK);
+}
@@ -0,0 +1,50 @@
+extern crate rqueue;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use common::{sync, connect, eventually};
+
+/// requests {path} and returns the status line and the body
+fn get(addr: SocketAddr, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    (head.lines().next().unwrap().to_owned(), body[4..].to_owned())
+}
+
+#[test]
+fn scrapes_reflect_traffic() {
+    let handle = common::spawn(common::builder().metrics(common::localhost()));
+    let addr = handle.metrics_addr().unwrap();
+
+    let mut subscriber = connect(&handle);
+    subscriber.subscribe(b"t").unwrap();
+    subscriber.subscribe(b"a\"b").unwrap();
+    sync(&mut subscriber);
+    let mut publisher = connect(&handle);
+    // answered once the worker has counted the delivery
+    publisher.publish_confirmed(b"t", b"counted").unwrap();
+    publisher.wait_confirmed().unwrap();
+    subscriber.next_message().unwrap();
+
+    let (status, body) = get(addr, "/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    for line in &["rqueue_connections 2", "rqueue_messages_received_total 4", "rqueue_messages_delivered_total 1",
+                  "rqueue_topics 2", "rqueue_subscriptions{topic=\"t\"} 1",
+                  "rqueue_subscriptions{topic=\"a\\\"b\"} 1", "rqueue_worker_queue_depth{worker=\"1\"} 0"] {
+        assert!(body.lines().any(|l| l == *line), "{:?} missing from\n{}", line, body);
+    }
+
+    drop(subscriber);
+    eventually("the disconnect", || handle.metrics().render().lines().any(|l| l == "rqueue_topics 0"));
+    let body = handle.metrics().render();
+    assert!(body.lines().any(|l| l == "rqueue_connections 1"), "{}", body);
+    assert!(!body.contains("topic=\"t\""), "{}", body);
+
+    assert_eq!(get(addr, "/").0, "HTTP/1.1 404 Not Found");
+    handle.shutdown().unwrap();
+}
@@ -0,0 +1,127 @@
+extern crate rqueue;
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::Duration;
+use rqueue::client::Client;
+use rqueue::protocol::{self, NOTIFICATION, AUTH_OK, PUBACK, PUBNACK, ERR_PERMISSION_DENIED, ERR_BAD_REQUEST};
+use rqueue::server::ServerHandle;
+use common::{sync, read_frame};
+
+fn spawn_server() -> ServerHandle {
+    common::spawn(common::with_users(common::builder(), &["app"], "[user.app]\npublish = [\"orders.*\"]\nsubscribe = [\">\"]\n"))
+}
+
+fn connect(handle: &ServerHandle) -> Client {
+    common::connect_as(handle, "app")
+}
+
+#[test]
+fn confirmed_publishes_are_acknowledged_and_delivered_as_notifications() {
+    let handle = spawn_server();
+    let mut subscriber = connect(&handle);
+    subscriber.subscribe(b"orders.new").unwrap();
+    sync(&mut subscriber);
+
+    let mut publisher = connect(&handle);
+    let seqs = (0..10u8).map(|i| publisher.publish_confirmed(b"orders.new", &[i]).unwrap()).collect::<Vec<_>>();
+    assert_eq!(seqs, (0..10).collect::<Vec<_>>());
+    assert_eq!(publisher.unconfirmed(), 10);
+    publisher.wait_confirmed().unwrap();
+    assert_eq!(publisher.unconfirmed(), 0);
+
+    // the sequence number is only for the publisher. the two workers may deliver out of order
+    let mut contents = (0..10).map(|_| {
+        let message = subscriber.next_message().unwrap();
+        assert_eq!(message.m_type, NOTIFICATION);
+        assert_eq!(message.topic(), Some(&b"orders.new"[..]));
+        message.content().unwrap().to_vec()
+    }).collect::<Vec<_>>();
+    contents.sort();
+    assert_eq!(contents, (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn denied_publishes_are_rejected_by_sequence_number() {
+    let handle = spawn_server();
+    let mut publisher = connect(&handle);
+    publisher.publish_confirmed(b"orders.new", b"ok").unwrap();
+    publisher.publish_confirmed(b"invoices.new", b"no").unwrap();
+    publisher.publish_confirmed(b"$SYS.clients", b"no").unwrap();
+
+    assert_eq!(publisher.wait_confirmed().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert_eq!(publisher.wait_confirmed().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    publisher.wait_confirmed().unwrap();
+
+    // the answers can be read as messages too
+    let seq = publisher.publish_confirmed(b"invoices.new", b"no").unwrap();
+    let nack = publisher.next_message().unwrap();
+    assert_eq!(nack.nack().map(|(seq, code, _)| (seq, code)), Some((seq, ERR_PERMISSION_DENIED)));
+    let seq = publisher.publish_confirmed(b"orders.new", b"ok").unwrap();
+    let ack = publisher.next_message().unwrap();
+    assert_eq!((ack.m_type, ack.ack()), (PUBACK, Some(seq)));
+    assert_eq!(publisher.unconfirmed(), 0);
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn messages_that_arrive_while_waiting_are_kept() {
+    let handle = spawn_server();
+    let mut client = connect(&handle);
+    client.subscribe(b"orders.new").unwrap();
+    sync(&mut client);
+
+    client.publish_confirmed(b"orders.new", b"mine").unwrap();
+    client.wait_confirmed().unwrap();
+    assert_eq!(client.next_message().unwrap().content(), Some(&b"mine"[..]));
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn publishes_with_a_malformed_topic_are_bad_requests() {
+    let handle = spawn_server();
+    let mut publisher = common::connect_raw(handle.local_addr());
+    publisher.write_all(&protocol::auth_message(b"", b"app")).unwrap();
+    assert_eq!(read_frame(&mut publisher).0, AUTH_OK);
+
+    // sequence number 7, then a topic length of 9 with nothing after it
+    publisher.write_all(&[0, 5, protocol::PUBLISH, 0, 0, 0, 7, 9]).unwrap();
+    let (m_type, payload) = read_frame(&mut publisher);
+    assert_eq!(m_type, PUBNACK);
+    assert_eq!(&payload[..5], &[0, 0, 0, 7, ERR_BAD_REQUEST]);
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn publishers_that_never_read_their_answers_hold_up_no_one() {
+    let handle = spawn_server();
+    let mut publisher = common::connect_raw(handle.local_addr());
+    publisher.write_all(&protocol::auth_message(b"", b"app")).unwrap();
+    assert_eq!(read_frame(&mut publisher).0, AUTH_OK);
+
+    // publishes until the server stops reading, without reading a single answer
+    let mut writer = publisher.try_clone().unwrap();
+    writer.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+    let flood = thread::spawn(move || {
+        let frame = protocol::publish_message(0, b"orders.flood", b"");
+        (0..).take_while(|_| writer.write_all(&frame).is_ok()).count()
+    });
+    let published = flood.join().unwrap();
+
+    let mut subscriber = connect(&handle);
+    subscriber.subscribe(b"orders.new").unwrap();
+    sync(&mut subscriber);
+    let mut other = connect(&handle);
+    other.publish_confirmed(b"orders.new", b"served").unwrap();
+    other.wait_confirmed().unwrap();
+    assert_eq!(subscriber.next_message().unwrap().content(), Some(&b"served"[..]));
+
+    // every whole PUBLISH is answered once the publisher reads
+    for _ in 0..published {
+        assert_eq!(read_frame(&mut publisher).0, PUBACK);
+    }
+    handle.shutdown().unwrap();
+}
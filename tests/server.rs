@@ -0,0 +1,286 @@
+extern crate rqueue;
+
+mod common;
+
+use std::env;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use rqueue::protocol;
+use rqueue::client::Client;
+use rqueue::server::{ServerHandle, ListenerConfig};
+use rqueue::transport::ListenAddr;
+use common::{sync, sync_raw};
+
+fn spawn_server() -> ServerHandle {
+    common::spawn(common::builder())
+}
+
+fn connect(handle: &ServerHandle) -> TcpStream {
+    common::connect_raw(handle.local_addr())
+}
+
+#[test]
+fn spawned_server_binds_an_ephemeral_port() {
+    let handle = spawn_server();
+    assert!(handle.port() != 0);
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn notifications_reach_subscribers() {
+    let handle = spawn_server();
+    let mut subscriber = connect(&handle);
+    let mut publisher = connect(&handle);
+
+    subscriber.write_all(&protocol::subscribe_message(b"weather")).unwrap();
+    // there is no acknowledgement for a SUBSCRIBE
+    sync_raw(&mut subscriber);
+
+    let message = protocol::notify_message(b"weather", b"sunny");
+    publisher.write_all(&message).unwrap();
+
+    let mut received = vec![0; message.len()];
+    subscriber.read_exact(&mut received).unwrap();
+    assert_eq!(received, message);
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn simultaneous_connects_are_all_accepted() {
+    let handle = spawn_server();
+    let mut subscriber = connect(&handle);
+    subscriber.write_all(&protocol::subscribe_message(b"t")).unwrap();
+    sync_raw(&mut subscriber);
+
+    let mut publishers = (0..8).map(|_| connect(&handle)).collect::<Vec<_>>();
+    let message = protocol::notify_message(b"t", b"x");
+    for publisher in publishers.iter_mut() {
+        publisher.write_all(&message).unwrap();
+    }
+
+    let mut received = vec![0; message.len() * publishers.len()];
+    subscriber.read_exact(&mut received).unwrap();
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn shutdown_drains_and_notifies_clients() {
+    let handle = common::spawn(common::builder().notify_shutdown(true));
+    let mut subscriber = connect(&handle);
+    let mut publisher = connect(&handle);
+    subscriber.write_all(&protocol::subscribe_message(b"t")).unwrap();
+    sync_raw(&mut subscriber);
+
+    // the sync makes sure the notification has been handed to the workers, not that it went out
+    let message = protocol::notify_message(b"t", b"last words");
+    publisher.write_all(&message).unwrap();
+    sync_raw(&mut publisher);
+
+    let summary = handle.shutdown().unwrap();
+    assert_eq!(summary.connections_accepted, 2);
+    assert_eq!(summary.messages_received, 4);
+    assert_eq!(summary.workers_drained, 2);
+    assert_eq!(summary.workers_abandoned, 0);
+
+    // everything routed before the shutdown arrives ahead of the SHUTDOWN frame
+    let mut received = vec![0; message.len()];
+    subscriber.read_exact(&mut received).unwrap();
+    assert_eq!(received, message);
+    let mut frame = [0; 3];
+    subscriber.read_exact(&mut frame).unwrap();
+    assert_eq!(frame.to_vec(), protocol::shutdown_message());
+}
+
+#[test]
+fn oversized_payloads_disconnect_the_sender() {
+    let handle = common::spawn(common::builder().workers(1).max_payload(16));
+    let mut client = connect(&handle);
+    client.write_all(&protocol::notify_message(b"t", &[0; 64])).unwrap();
+
+    // unread bytes left behind make the close a reset rather than an EOF
+    let mut buf = [0; 1];
+    match client.read(&mut buf) {
+        Ok(0) => (),
+        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => (),
+        other => panic!("expected the connection to be closed, got {:?}", other)
+    }
+}
+
+#[test]
+fn topics_longer_than_255_bytes_are_bad_requests() {
+    let handle = spawn_server();
+    let mut client = connect(&handle);
+    client.write_all(&protocol::subscribe_message(&[b'x'; 256])).unwrap();
+    let (m_type, payload) = common::read_frame(&mut client);
+    assert_eq!((m_type, payload[0]), (protocol::ERROR, protocol::ERR_BAD_REQUEST));
+
+    // the client stays connected, without the subscription
+    sync_raw(&mut client);
+    assert_eq!(handle.metrics().snapshot().topics, 0);
+}
+
+#[test]
+fn listeners_share_subscriptions_and_keep_their_own_limits() {
+    let handle = common::spawn(common::builder()
+        .listen(ListenerConfig::new("[::1]:0".parse::<SocketAddr>().unwrap()).max_connections(1)));
+    let addrs = handle.local_addrs().iter().filter_map(|a| a.tcp()).collect::<Vec<_>>();
+    assert!(addrs[1].is_ipv6());
+
+    let mut subscriber = common::connect_raw(addrs[1]);
+    subscriber.write_all(&protocol::subscribe_message(b"t")).unwrap();
+    sync_raw(&mut subscriber);
+
+    // the IPv6 listener is full, the IPv4 one is not
+    let mut rejected = common::connect_raw(addrs[1]);
+    let mut refusal = Vec::new();
+    rejected.read_to_end(&mut refusal).unwrap();
+    assert_eq!(refusal, protocol::error_message(protocol::ERR_TOO_MANY_CONNECTIONS, "too many connections"));
+
+    let mut publisher = connect(&handle);
+    let message = protocol::notify_message(b"t", b"across listeners");
+    publisher.write_all(&message).unwrap();
+
+    let mut received = vec![0; message.len()];
+    subscriber.read_exact(&mut received).unwrap();
+    assert_eq!(received, message);
+}
+
+#[test]
+fn fan_out_crosses_tcp_and_unix_clients() {
+    let path = env::temp_dir().join(format!("rqueue-test-{}.sock", process::id()));
+    let handle = common::spawn(common::builder().bind(ListenAddr::Unix(path.clone())));
+    assert_eq!(handle.local_addrs()[1], ListenAddr::Unix(path.clone()));
+
+    let mut clients = [common::connect(&handle), Client::connect_unix(&path).unwrap()];
+    clients[1].set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    for client in clients.iter_mut() {
+        client.subscribe(b"t").unwrap();
+        sync(client);
+    }
+
+    // each client publishes once and so receives both notifications
+    clients[0].publish(b"t", b"over tcp").unwrap();
+    clients[1].publish(b"t", b"over unix").unwrap();
+    for client in clients.iter_mut() {
+        let mut contents = (0..2).map(|_| client.next_message().unwrap().content().unwrap().to_vec())
+                                 .collect::<Vec<_>>();
+        contents.sort();
+        assert_eq!(contents, vec![b"over tcp".to_vec(), b"over unix".to_vec()]);
+    }
+
+    handle.shutdown().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn sys_topics_are_published_and_read_only() {
+    let handle = common::spawn(common::builder().sys_interval(Duration::from_millis(200)));
+    let mut client = common::connect(&handle);
+    client.subscribe(b"$SYS.clients").unwrap();
+
+    let stats = client.next_message().unwrap();
+    assert_eq!(stats.topic(), Some(&b"$SYS.clients"[..]));
+    assert_eq!(stats.content(), Some(&b"1"[..]));
+
+    client.publish(b"$SYS.clients", b"1000").unwrap();
+    // more statistics may arrive ahead of the reply
+    let reply = (0..).map(|_| client.next_message().unwrap()).find(|m| m.m_type == protocol::ERROR).unwrap();
+    assert_eq!(reply.error().unwrap().0, protocol::ERR_PERMISSION_DENIED);
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn full_worker_queues_pause_publishers_without_losing_notifications() {
+    let handle = common::spawn(common::builder().workers(1).queue_depth(1));
+    let mut subscriber = common::connect(&handle);
+    subscriber.subscribe(b"burst").unwrap();
+    sync(&mut subscriber);
+
+    // sent in one go, so the server reads them faster than a single worker delivers them
+    let burst = (0..2000u32).flat_map(|i| protocol::notify_message(b"burst", &i.to_be_bytes())).collect::<Vec<_>>();
+    let mut publisher = connect(&handle);
+    publisher.write_all(&burst).unwrap();
+
+    for i in 0..2000u32 {
+        let message = subscriber.next_message().unwrap();
+        assert_eq!(message.content(), Some(&i.to_be_bytes()[..]));
+    }
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn every_publish_read_is_answered_before_shutdown() {
+    // a unix socket, unlike tcp, keeps what was already written to it readable once it is reset
+    let path = env::temp_dir().join(format!("rqueue-test-{}-shutdown.sock", process::id()));
+    let handle = common::spawn(common::builder().workers(1).queue_depth(1).bind(ListenAddr::Unix(path.clone())));
+
+    // the publisher sends faster than the worker answers, so when the server stops it most likely
+    // has one PUBLISH with the worker and the next held back
+    let mut publisher = UnixStream::connect(&path).unwrap();
+    publisher.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let mut writer = publisher.try_clone().unwrap();
+    let flood = thread::spawn(move || {
+        for seq in 0.. {
+            if writer.write_all(&protocol::publish_message(seq, b"t", b"")).is_err() {
+                break;
+            }
+        }
+    });
+    // the answers are read all along, or the server would stop reading the flood
+    let answered = thread::spawn(move || {
+        let mut answers = Vec::new();
+        if let Err(e) = publisher.read_to_end(&mut answers) {
+            // the server went away with part of the flood unread
+            assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+        }
+        answers
+    });
+    common::eventually("the flood", || handle.metrics().snapshot().messages_received > 1000);
+    let summary = handle.shutdown().unwrap();
+    flood.join().unwrap();
+
+    let mut answers = answered.join().unwrap();
+    let mut seqs = Vec::new();
+    while !answers.is_empty() {
+        let len = protocol::PREAMBLE_SZ + protocol::u8_2_to_usize(&answers[..2]);
+        assert!(answers[2] == protocol::PUBACK || answers[2] == protocol::PUBNACK, "{:?}", &answers[..len]);
+        seqs.push(protocol::u8_4_to_u32(&answers[3..7]) as u64);
+        answers.drain(..len);
+    }
+    seqs.sort();
+    assert_eq!(seqs, (0..summary.messages_received).collect::<Vec<_>>());
+}
+
+#[test]
+fn clients_that_never_read_are_dropped_once_shutdown_runs_out_of_time() {
+    let handle = common::spawn(common::builder()
+        .max_subscriptions(0)
+        .drain_timeout(Duration::from_millis(200))
+        .notify_shutdown(true));
+    let mut reader = connect(&handle);
+    sync_raw(&mut reader);
+
+    // every SUBSCRIBE is refused with an ERROR longer than itself, none of which are read, until
+    // the server stops reading
+    let stalled = connect(&handle);
+    let mut writer = stalled.try_clone().unwrap();
+    writer.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+    let frame = protocol::subscribe_message(b"t");
+    thread::spawn(move || while writer.write_all(&frame).is_ok() {}).join().unwrap();
+
+    let started = Instant::now();
+    handle.shutdown().unwrap();
+    assert!(started.elapsed() < common::TIMEOUT);
+    // clients that keep up still hear about it
+    let mut frame = [0; 3];
+    reader.read_exact(&mut frame).unwrap();
+    assert_eq!(frame.to_vec(), protocol::shutdown_message());
+}
@@ -0,0 +1,127 @@
+extern crate proptest;
+extern crate rqueue;
+
+use std::collections::HashMap;
+use proptest::prelude::*;
+use rqueue::slice_map::{SliceMap, Entry, Hashing};
+
+#[derive(Debug, Clone)]
+enum Op {
+    Insert(Vec<u8>, u32),
+    Get(Vec<u8>),
+    Remove(Vec<u8>),
+    Delete(Vec<u8>),
+    ModifyOrElse(Vec<u8>, u32),
+    Apply(Vec<u8>),
+    EntryOrInsert(Vec<u8>, u32),
+    EntryRemove(Vec<u8>),
+    /// keeps values divisible by the argument
+    Retain(u32),
+    Drain
+}
+
+/// keys from a small space so that operations often hit each other, with the odd empty or long one
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        8 => (0..64u32).prop_map(|i| format!("t.{}", i).into_bytes()),
+        1 => Just(Vec::new()),
+        1 => prop::collection::vec(any::<u8>(), 0..255)
+    ]
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        8 => (key(), any::<u32>()).prop_map(|(k, v)| Op::Insert(k, v)),
+        4 => key().prop_map(Op::Get),
+        3 => key().prop_map(Op::Remove),
+        1 => key().prop_map(Op::Delete),
+        2 => (key(), any::<u32>()).prop_map(|(k, v)| Op::ModifyOrElse(k, v)),
+        1 => key().prop_map(Op::Apply),
+        2 => (key(), any::<u32>()).prop_map(|(k, v)| Op::EntryOrInsert(k, v)),
+        1 => key().prop_map(Op::EntryRemove),
+        1 => (2..5u32).prop_map(Op::Retain),
+        1 => Just(Op::Drain)
+    ]
+}
+
+/// runs {ops} against a SliceMap and a HashMap, checking that every result and the final contents
+/// agree
+fn check(hashing: Hashing, ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let mut map = SliceMap::with_hasher(hashing);
+    let mut model: HashMap<Vec<u8>, u32> = HashMap::new();
+    for op in ops {
+        match op {
+            Op::Insert(k, v) => {
+                map.insert(&k, v);
+                model.insert(k, v);
+            }
+            Op::Get(k) => prop_assert_eq!(map.get(&k), model.get(&k)),
+            Op::Remove(k) => prop_assert_eq!(map.remove(&k), model.remove(&k)),
+            Op::Delete(k) => prop_assert_eq!(map.delete(&k), model.remove(&k).is_some()),
+            Op::ModifyOrElse(k, v) => {
+                map.modify_or_else(&k, |x| *x = x.wrapping_add(1), || v);
+                let x = model.entry(k).or_insert_with(|| v.wrapping_sub(1));
+                *x = x.wrapping_add(1);
+            }
+            Op::Apply(k) => {
+                map.apply(&k, |x| *x ^= 1);
+                if let Some(x) = model.get_mut(&k) {
+                    *x ^= 1;
+                }
+            }
+            Op::EntryOrInsert(k, v) => {
+                prop_assert_eq!(*map.entry(&k).or_insert(v), *model.entry(k).or_insert(v));
+            }
+            Op::EntryRemove(k) => {
+                let removed = match map.entry(&k) {
+                    Entry::Occupied(e) => Some(e.remove()),
+                    Entry::Vacant(_) => None
+                };
+                prop_assert_eq!(removed, model.remove(&k));
+            }
+            Op::Retain(n) => {
+                map.retain(|_, v| *v % n == 0);
+                model.retain(|_, v| *v % n == 0);
+            }
+            Op::Drain => {
+                let mut drained = map.drain().collect::<Vec<_>>();
+                let mut expected = model.drain().collect::<Vec<_>>();
+                drained.sort();
+                expected.sort();
+                prop_assert_eq!(drained, expected);
+            }
+        }
+        prop_assert_eq!(map.len(), model.len());
+    }
+
+    let mut contents = map.iter().map(|(k, v)| (k.to_vec(), *v)).collect::<Vec<_>>();
+    let mut expected = model.into_iter().collect::<Vec<_>>();
+    contents.sort();
+    expected.sort();
+    prop_assert_eq!(contents, expected);
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn behaves_like_a_hash_map(ops in prop::collection::vec(op(), 0..200)) {
+        check(Hashing::Fast, ops)?;
+    }
+
+    #[test]
+    fn behaves_like_a_hash_map_with_keyed_hashing(ops in prop::collection::vec(op(), 0..200)) {
+        check(Hashing::keyed(), ops)?;
+    }
+}
+
+/// enough distinct keys to take the table through growing and shrinking. too slow for Miri
+#[test]
+#[cfg_attr(miri, ignore)]
+fn behaves_like_a_hash_map_while_resizing() {
+    let mut runner = proptest::test_runner::TestRunner::new(ProptestConfig::with_cases(8));
+    let many = (0..20_000u32, any::<u32>()).prop_map(|(i, v)| Op::Insert(format!("k.{}", i).into_bytes(), v));
+    let fewer = (0..20_000u32).prop_map(|i| Op::Remove(format!("k.{}", i).into_bytes()));
+    let ops = (prop::collection::vec(many, 8000), prop::collection::vec(fewer, 20_000), prop::collection::vec(op(), 100))
+        .prop_map(|(a, b, c)| a.into_iter().chain(b).chain(c).collect::<Vec<_>>());
+    runner.run(&ops, |ops| check(Hashing::Fast, ops)).unwrap();
+}
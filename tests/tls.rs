@@ -0,0 +1,93 @@
+extern crate rqueue;
+extern crate rcgen;
+
+mod common;
+
+use std::{env, fs, process};
+use std::path::PathBuf;
+use std::net::SocketAddr;
+use rqueue::client::Client;
+use rqueue::server::{Server, ServerHandle, ListenerConfig};
+use rqueue::tls::{self, TlsConfig};
+use common::{TIMEOUT, sync};
+
+/// writes a self-signed certificate for localhost and its key, returning their paths. tests run
+/// in parallel so each one needs its own {name}
+fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+    let dir = env::temp_dir().join(format!("rqueue-tls-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let (cert_path, key_path) = (dir.join(format!("{}.pem", name)), dir.join(format!("{}.key", name)));
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+fn spawn_server(tls: TlsConfig) -> ServerHandle {
+    common::spawn(common::builder().listen(ListenerConfig::new(common::localhost()).tls(tls)))
+}
+
+fn tls_addr(handle: &ServerHandle) -> SocketAddr {
+    handle.local_addrs()[1].tcp().unwrap()
+}
+
+#[test]
+fn tls_and_plain_clients_share_topics() {
+    let (cert, key) = self_signed("shared-server");
+    let handle = spawn_server(TlsConfig::new(&cert, &key));
+    let config = tls::client_config(&cert, None).unwrap();
+
+    let mut secure = Client::connect_tls(tls_addr(&handle), "localhost", config).unwrap();
+    secure.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut plain = common::connect(&handle);
+    for client in [&mut secure, &mut plain].iter_mut() {
+        client.subscribe(b"t").unwrap();
+        sync(client);
+    }
+
+    secure.publish(b"t", b"encrypted").unwrap();
+    plain.publish(b"t", b"in the clear").unwrap();
+    for client in [&mut secure, &mut plain].iter_mut() {
+        let mut contents = (0..2).map(|_| client.next_message().unwrap().content().unwrap().to_vec())
+                                 .collect::<Vec<_>>();
+        contents.sort();
+        assert_eq!(contents, vec![b"encrypted".to_vec(), b"in the clear".to_vec()]);
+    }
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn client_certificates_are_checked_when_configured() {
+    let (cert, key) = self_signed("mutual-server");
+    let (client_cert, client_key) = self_signed("mutual-client");
+    let handle = spawn_server(TlsConfig::new(&cert, &key).client_ca(&client_cert));
+
+    // with TLS 1.3 the server's verdict on the client only arrives after the handshake
+    let anonymous = tls::client_config(&cert, None).unwrap();
+    let rejected = Client::connect_tls(tls_addr(&handle), "localhost", anonymous).and_then(|mut c| {
+        c.set_read_timeout(Some(TIMEOUT))?;
+        c.subscribe(b"t")?;
+        c.next_message()
+    });
+    assert!(rejected.is_err());
+
+    let identified = tls::client_config(&cert, Some((&client_cert, &client_key))).unwrap();
+    let mut client = Client::connect_tls(tls_addr(&handle), "localhost", identified).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client.subscribe(b"t").unwrap();
+    sync(&mut client);
+    client.publish(b"t", b"mutual").unwrap();
+    assert_eq!(client.next_message().unwrap().content(), Some(&b"mutual"[..]));
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn unreadable_certificates_fail_the_build() {
+    let missing = env::temp_dir().join("rqueue-tls-missing.pem");
+    let result = Server::builder()
+        .listen(ListenerConfig::new(common::localhost()).tls(TlsConfig::new(&missing, &missing)))
+        .build();
+    assert!(result.is_err());
+}